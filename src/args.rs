@@ -0,0 +1,218 @@
+//! Construction of the `cargo build` command line from our own arguments.
+
+/// Options of cargo-dfu which take a value and must not be forwarded to cargo.
+const VALUE_FLAGS: &[&str] = &[
+    "--pid",
+    "--vid",
+    "--chip",
+    "--artifact",
+    "--retries",
+    "--delay",
+    "--max-retries-per-phase",
+    "--deadline",
+    "--post-flash-delay",
+    "--interface-name",
+    "--interface",
+    "--alt",
+    "--selftest",
+    "--selftest-port",
+    "--selftest-timeout",
+    "--baud-rate",
+    "--address-source",
+    "--output",
+    "--probe-rs-chip",
+    "--enter-timeout",
+    "--port-path",
+    "--address",
+    "--hex-fill",
+    "--fill-byte",
+    "--firmware",
+    "--serial",
+    "--detach-vid",
+    "--detach-pid",
+    "--timeout",
+    "--transfer-size",
+    "--upload",
+    "--length",
+    "--color",
+    "--format",
+    "--bus",
+    "--usb-address",
+    "--erase",
+    "--max-size",
+    "--search-timeout",
+    "--hash",
+];
+
+/// Options of cargo-dfu whose value is optional and can only be given as `--flag=value`.
+const OPTIONAL_VALUE_FLAGS: &[&str] = &["--verify"];
+
+/// Options of cargo-dfu without a value which must not be forwarded to cargo.
+const SWITCHES: &[&str] = &[
+    "--no-build",
+    "--list-chips",
+    "--list-alts",
+    "--list-devices",
+    "--reset",
+    "--detach",
+    "--dry-run",
+    "--all",
+    "--mass-erase",
+    "--search-before-build",
+    "--strict",
+    "--wait",
+];
+
+/// Phases of cargo-dfu to run alone, only recognized as the first argument.
+pub const PHASES: &[&str] = &["build", "flash"];
+
+/// Returns the arguments to pass to `cargo build`, given the arguments following `cargo dfu`.
+///
+/// The phase and both the `--flag value` and the `--flag=value` forms of our own options are
+/// removed, everything else is forwarded untouched and in order.
+pub fn cargo_build_args<I>(args: I) -> Vec<String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter().peekable();
+    let mut build_args = vec![];
+
+    if args
+        .peek()
+        .is_some_and(|arg| PHASES.contains(&arg.as_str()))
+    {
+        args.next();
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.split_once('=') {
+            Some((flag, _)) if VALUE_FLAGS.contains(&flag) => {}
+            Some((flag, _)) if OPTIONAL_VALUE_FLAGS.contains(&flag) => {}
+            None if VALUE_FLAGS.contains(&arg.as_str()) => {
+                args.next();
+            }
+            None if SWITCHES.contains(&arg.as_str()) => {}
+            None if OPTIONAL_VALUE_FLAGS.contains(&arg.as_str()) => {}
+            _ => build_args.push(arg),
+        }
+    }
+
+    build_args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_args(args: &str) -> Vec<String> {
+        cargo_build_args(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn forwards_cargo_flags() {
+        assert_eq!(
+            build_args("--release --bin firmware --features a,b"),
+            vec!["--release", "--bin", "firmware", "--features", "a,b"]
+        );
+    }
+
+    #[test]
+    fn forwards_package_and_features() {
+        assert_eq!(
+            build_args(
+                "-p firmware --chip stm32 --features a,b --features=c \
+                 --no-default-features --all-features --package=other \
+                 --manifest-path ../board/Cargo.toml"
+            ),
+            vec![
+                "-p",
+                "firmware",
+                "--features",
+                "a,b",
+                "--features=c",
+                "--no-default-features",
+                "--all-features",
+                "--package=other",
+                "--manifest-path",
+                "../board/Cargo.toml"
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_separated_flags() {
+        assert_eq!(
+            build_args("--pid 0xdf11 --release --vid 0x0483 --chip stm32"),
+            vec!["--release"]
+        );
+    }
+
+    #[test]
+    fn removes_joined_flags() {
+        assert_eq!(
+            build_args("--chip=stm32 --release --pid=0xdf11 --target=thumbv7em-none-eabihf"),
+            vec!["--release", "--target=thumbv7em-none-eabihf"]
+        );
+    }
+
+    #[test]
+    fn removes_joined_flags_between_cargo_flags() {
+        assert_eq!(
+            build_args("--release --pid=0x0483 --features foo --vid=0xdf11"),
+            vec!["--release", "--features", "foo"]
+        );
+    }
+
+    #[test]
+    fn keeps_short_flags() {
+        assert_eq!(
+            build_args("-p firmware --chip stm32 -v"),
+            vec!["-p", "firmware", "-v"]
+        );
+    }
+
+    #[test]
+    fn removes_repeated_flags() {
+        assert_eq!(
+            build_args("--chip stm32 --release --chip=gd32vf103 --chip stm32"),
+            vec!["--release"]
+        );
+    }
+
+    #[test]
+    fn removes_flags_at_the_end() {
+        assert_eq!(build_args("--release --pid 0xdf11"), vec!["--release"]);
+        assert_eq!(build_args("--release --pid"), vec!["--release"]);
+        assert_eq!(build_args("--release --no-build"), vec!["--release"]);
+    }
+
+    #[test]
+    fn never_leaks_our_flags() {
+        let args = build_args(
+            "--artifact a --firmware f.bin --no-build --reset --serial 0042 \
+             --detach --detach-vid 0x1209 --detach-pid=0x0001 --retries 1 --delay=5 --max-retries-per-phase 2 \
+             --deadline 3 --post-flash-delay 4 --interface-name DFU --interface 0 --alt 1 \
+             --selftest OK --selftest-port /dev/ttyACM0 --selftest-timeout 5 --baud-rate 9600 \
+             --address-source virtual --output a.srec \
+             --probe-rs-chip STM32F411RETx --enter-timeout 9 --verify=after-reset \
+             --port-path 1.4.2 --address 0x08008000 \
+             --hex-fill 0 --fill-byte=0xff --timeout 10000 --transfer-size=2048 --upload dump.bin --length 1024 --color=never --format json --bus 1 --usb-address=7 --erase 0x08004000 --mass-erase --search-before-build --max-size=0x100000 --search-timeout 30 --hash sha256 --strict --wait --all --dry-run --list-alts --list-chips --list-devices",
+        );
+        assert!(args.is_empty(), "{:?}", args);
+    }
+
+    #[test]
+    fn keeps_the_argument_after_optional_value_flags() {
+        assert_eq!(build_args("--verify --release"), vec!["--release"]);
+        assert_eq!(
+            build_args("--verify=immediate --release"),
+            vec!["--release"]
+        );
+    }
+
+    #[test]
+    fn removes_the_phase_in_first_position_only() {
+        assert_eq!(build_args("build --release"), vec!["--release"]);
+        assert_eq!(build_args("--features flash"), vec!["--features", "flash"]);
+    }
+}
@@ -0,0 +1,59 @@
+//! Checksums of flashed images, to tell from the logs which build went to a device.
+
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Checksum algorithms, CRC32 being cheap enough to always compute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    Crc32,
+    Sha256,
+}
+
+impl Hash {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hash::Crc32 => "crc32",
+            Hash::Sha256 => "sha256",
+        }
+    }
+
+    /// Returns the checksum of `data` as lowercase hex digits.
+    pub fn digest(self, data: &[u8]) -> String {
+        match self {
+            Hash::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+            Hash::Sha256 => Sha256::digest(data)
+                .iter()
+                .fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{:02x}", byte);
+                    hex
+                }),
+        }
+    }
+}
+
+impl std::str::FromStr for Hash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc32" => Ok(Hash::Crc32),
+            "sha256" => Ok(Hash::Sha256),
+            _ => Err(format!("unknown hash {:?}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_check_values() {
+        assert_eq!(Hash::Crc32.digest(b"123456789"), "cbf43926");
+        assert_eq!(
+            Hash::Sha256.digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
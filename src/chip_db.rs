@@ -0,0 +1,22 @@
+//! Flash geometry from the probe-rs chip database.
+
+use probe_rs::config::{get_target_by_name, MemoryRegion};
+use std::ops::Range;
+
+/// Returns the address range of the flash the chip boots from, or of its first flash otherwise.
+pub fn flash_region(chip: &str) -> Result<Range<u64>, String> {
+    let target = get_target_by_name(chip).map_err(|e| e.to_string())?;
+    let flash: Vec<_> = target
+        .memory_map
+        .iter()
+        .filter_map(MemoryRegion::as_nvm_region)
+        .filter(|region| !region.is_alias)
+        .collect();
+
+    flash
+        .iter()
+        .find(|region| region.is_boot_memory)
+        .or_else(|| flash.first())
+        .map(|region| region.range.clone())
+        .ok_or_else(|| format!("{} has no flash in the probe-rs database", target.name))
+}
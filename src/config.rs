@@ -0,0 +1,142 @@
+//! User chip definitions, read from `chips.toml` in the user configuration directory and from a
+//! project-local `.cargo-dfu.toml`.
+//!
+//! ```toml
+//! [chips]
+//! stm32h7 = [{ vid = 0x0483, pid = 0xdf11 }]
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub type ChipMap = HashMap<String, Vec<(u16, u16)>>;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Toml(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            ConfigError::Toml(path, e) => write!(f, "invalid config {:?}: {}", path, e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    chips: HashMap<String, Vec<Product>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Product {
+    vid: u16,
+    pid: u16,
+}
+
+/// Returns `builtin` with the chips of the config files merged over it, the project-local file
+/// found in `project_dir` or above taking precedence over the user one.
+pub fn chip_map(builtin: ChipMap, project_dir: &Path) -> Result<ChipMap, ConfigError> {
+    let mut chips = builtin;
+    for path in config_files(project_dir) {
+        if !path.is_file() {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        let config = parse(&text).map_err(|e| ConfigError::Toml(path.clone(), e))?;
+        chips.extend(config);
+    }
+
+    Ok(chips)
+}
+
+/// Returns the vid/pid pairs of `chip`, whose name is matched ignoring case.
+pub fn products<'a>(chips: &'a ChipMap, chip: &str) -> Option<&'a [(u16, u16)]> {
+    chips
+        .get(chip)
+        .or_else(|| {
+            chips
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(chip))
+                .map(|(_, products)| products)
+        })
+        .map(Vec::as_slice)
+}
+
+fn config_files(project_dir: &Path) -> Vec<PathBuf> {
+    let user = dirs::config_dir().map(|dir| dir.join("cargo-dfu").join("chips.toml"));
+    // A relative directory has no ancestors to walk up to.
+    let project_dir = project_dir
+        .canonicalize()
+        .unwrap_or_else(|_| project_dir.to_path_buf());
+    let project = project_dir
+        .ancestors()
+        .map(|dir| dir.join(".cargo-dfu.toml"))
+        .find(|path| path.is_file());
+
+    user.into_iter().chain(project).collect()
+}
+
+fn parse(text: &str) -> Result<ChipMap, toml::de::Error> {
+    let config: Config = toml::from_str(text)?;
+    Ok(config
+        .chips
+        .into_iter()
+        .map(|(name, products)| {
+            let products = products.into_iter().map(|p| (p.vid, p.pid)).collect();
+            (name, products)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chips() {
+        let chips = parse(
+            "[chips]\n\
+             stm32h7 = [{ vid = 0x0483, pid = 0xdf11 }]\n\
+             custom = [{ vid = 0x1209, pid = 0x0001 }, { vid = 0x1209, pid = 2 }]\n",
+        )
+        .unwrap();
+        assert_eq!(chips["stm32h7"], vec![(0x0483, 0xdf11)]);
+        assert_eq!(chips["custom"], vec![(0x1209, 1), (0x1209, 2)]);
+    }
+
+    #[test]
+    fn matches_chips_ignoring_case() {
+        let chips = parse("[chips]\nSTM32H7 = [{ vid = 0x0483, pid = 0xdf11 }]\n").unwrap();
+        for name in ["STM32H7", "stm32h7", "Stm32H7"] {
+            assert_eq!(products(&chips, name), Some(&[(0x0483, 0xdf11)][..]));
+        }
+        assert_eq!(products(&chips, "stm32"), None);
+    }
+
+    #[test]
+    fn accepts_empty_config() {
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_config() {
+        for text in [
+            "[chips]\nstm32 = [{ vid = 0x0483 }]",
+            "[chips]\nstm32 = [{ vid = 0x10000, pid = 1 }]",
+            "[chips]\nstm32 = [{ vid = 1, pid = 1, foo = 2 }]",
+            "[chips",
+            "[devices]",
+        ] {
+            assert!(parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Selection of the device to flash among the connected ones.
+
+use crate::config::ChipMap;
+use crate::utils::{serial_number, vendor_map, PortPath, UtilError};
+use rusb::{DeviceHandle, GlobalContext};
+
+/// Which connected device to flash.
+///
+/// A port path selects the device plugged in there, otherwise devices are matched by vid/pid, by
+/// the vid/pid pairs of the chip, or by any pair of `chips`. The bus, the address on the bus and
+/// the serial number narrow the choice down further.
+#[derive(Debug, Clone)]
+pub struct DeviceSelector {
+    pub vid_pid: Option<(u16, u16)>,
+    pub chip: Option<String>,
+    pub serial: Option<String>,
+    pub port_path: Option<PortPath>,
+    pub bus: Option<u8>,
+    /// Address of the device on its bus.
+    pub address: Option<u8>,
+    /// Vid/pid pairs of the known chips, the built-in ones by default.
+    pub chips: ChipMap,
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        DeviceSelector {
+            vid_pid: None,
+            chip: None,
+            serial: None,
+            port_path: None,
+            bus: None,
+            address: None,
+            chips: vendor_map(),
+        }
+    }
+}
+
+/// Opens the connected devices on the port path, or matching the vid/pid or chip of `selector`, or
+/// any device of its chips otherwise, on its bus and address if given.
+pub fn candidates(selector: &DeviceSelector) -> Vec<rusb::Result<DeviceHandle<GlobalContext>>> {
+    let products: Vec<(u16, u16)> = if let Some(vid_pid) = selector.vid_pid {
+        vec![vid_pid]
+    } else if let Some(c) = &selector.chip {
+        crate::config::products(&selector.chips, c)
+            .unwrap_or_default()
+            .to_vec()
+    } else {
+        selector.chips.values().flatten().copied().collect()
+    };
+
+    let devices = match rusb::devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            log::warn!("Listing the USB devices failed: {}", e);
+            return vec![];
+        }
+    };
+    devices
+        .iter()
+        .filter(|d| match &selector.port_path {
+            Some(path) => path.matches(d),
+            None => d
+                .device_descriptor()
+                .is_ok_and(|desc| products.contains(&(desc.vendor_id(), desc.product_id()))),
+        })
+        .filter(|d| selector.bus.is_none_or(|bus| bus == d.bus_number()))
+        .filter(|d| {
+            selector
+                .address
+                .is_none_or(|address| address == d.address())
+        })
+        .map(|d| d.open())
+        .collect()
+}
+
+/// Returns every connected device in DFU mode among the candidates, with the serial number of
+/// `selector` if any.
+pub fn find_devices(selector: &DeviceSelector) -> Vec<DeviceHandle<GlobalContext>> {
+    candidates(selector)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|d| {
+            !crate::dfu::in_runtime_mode(&d.device())
+                && (selector.serial.is_none()
+                    || serial_number(d).as_deref() == selector.serial.as_deref())
+        })
+        .collect()
+}
+
+/// Searches for a connected device in DFU mode among the candidates, with the serial number of
+/// `selector` if any. Fails if several devices match rather than picking one.
+pub fn find_device(
+    selector: &DeviceSelector,
+) -> Result<Option<DeviceHandle<GlobalContext>>, UtilError> {
+    let mut matches = find_devices(selector);
+    if matches.len() > 1 {
+        return Err(UtilError::AmbiguousDevices(
+            matches.iter().map(DeviceHandle::device).collect(),
+        ));
+    }
+    Ok(matches.pop())
+}
@@ -0,0 +1,662 @@
+//! DFU I/O over a device handle opened by cargo-dfu itself.
+//!
+//! `dfu_libusb::DfuLibusb::open` looks the device up again by vid/pid, which may pick another
+//! board than the one we found, and hides its handle. This mirrors it on top of our own handle.
+
+use dfu_core::functional_descriptor::FunctionalDescriptor;
+use dfu_core::memory_layout::MemoryLayout;
+use dfu_core::{State, Status};
+use dfu_libusb::Error;
+use rusb::constants::{LIBUSB_ENDPOINT_IN, LIBUSB_REQUEST_GET_DESCRIPTOR};
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, Recipient, RequestType};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+pub type Dfu = dfu_core::sync::DfuSync<DfuDevice, Error>;
+
+/// Timeout of the control transfers unless given otherwise.
+pub const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Class and subclass of a DFU interface.
+pub const DFU_CLASS: (u8, u8) = (0xfe, 0x01);
+/// Interface protocol of a device running its application.
+pub const PROTOCOL_RUNTIME: u8 = 0x01;
+/// Interface protocol of a device in DFU mode.
+pub const PROTOCOL_DFU: u8 = 0x02;
+
+/// DFU class requests.
+const REQUEST_DETACH: u8 = 0x00;
+const REQUEST_DNLOAD: u8 = 0x01;
+const REQUEST_UPLOAD: u8 = 0x02;
+const REQUEST_GETSTATUS: u8 = 0x03;
+const REQUEST_CLRSTATUS: u8 = 0x04;
+const REQUEST_ABORT: u8 = 0x06;
+
+/// DfuSe command setting the address pointer, sent as a DNLOAD to block 0.
+const DFUSE_SET_ADDRESS: u8 = 0x21;
+/// DfuSe command erasing the page at the given address, or the whole flash without one.
+const DFUSE_ERASE: u8 = 0x41;
+
+/// Transfer size to use when the device has no functional descriptor.
+const DEFAULT_TRANSFER_SIZE: u16 = 1024;
+
+/// Parameters of the download transfers.
+#[derive(Clone, Copy, Debug)]
+pub struct Transfer {
+    /// Timeout of every control transfer.
+    pub timeout: Duration,
+    /// Bytes per block, overriding the `wTransferSize` of the functional descriptor.
+    pub size: Option<u16>,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self {
+            timeout: TIMEOUT,
+            size: None,
+        }
+    }
+}
+
+/// Standard GET_INTERFACE request, returning the current alt setting of an interface.
+const REQUEST_GET_INTERFACE: u8 = 0x0a;
+
+pub struct DfuDevice {
+    usb: RefCell<DeviceHandle<GlobalContext>>,
+    memory_layout: MemoryLayout,
+    timeout: Duration,
+    iface: u16,
+    functional_descriptor: FunctionalDescriptor,
+}
+
+impl dfu_core::DfuIo for DfuDevice {
+    type Read = usize;
+    type Write = usize;
+    type Reset = ();
+    type Error = Error;
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &mut [u8],
+    ) -> Result<Self::Read, Self::Error> {
+        let request_type = request_type | LIBUSB_ENDPOINT_IN;
+        Ok(self.usb.borrow().read_control(
+            request_type,
+            request,
+            value,
+            self.iface,
+            buffer,
+            self.timeout,
+        )?)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &[u8],
+    ) -> Result<Self::Write, Self::Error> {
+        Ok(self.usb.borrow().write_control(
+            request_type,
+            request,
+            value,
+            self.iface,
+            buffer,
+            self.timeout,
+        )?)
+    }
+
+    fn usb_reset(&self) -> Result<Self::Reset, Self::Error> {
+        Ok(self.usb.borrow_mut().reset()?)
+    }
+
+    fn memory_layout(&self) -> &dfu_core::memory_layout::mem {
+        self.memory_layout.as_ref()
+    }
+
+    fn functional_descriptor(&self) -> &FunctionalDescriptor {
+        &self.functional_descriptor
+    }
+}
+
+/// The handle operations needed to select an alt setting.
+pub trait AltSelect {
+    fn claim_interface(&mut self, iface: u8) -> rusb::Result<()>;
+    fn set_alternate_setting(&mut self, iface: u8, alt: u8) -> rusb::Result<()>;
+    fn alternate_setting(&self, iface: u8) -> rusb::Result<u8>;
+}
+
+impl AltSelect for DeviceHandle<GlobalContext> {
+    fn claim_interface(&mut self, iface: u8) -> rusb::Result<()> {
+        DeviceHandle::claim_interface(self, iface)
+    }
+
+    fn set_alternate_setting(&mut self, iface: u8, alt: u8) -> rusb::Result<()> {
+        DeviceHandle::set_alternate_setting(self, iface, alt)
+    }
+
+    fn alternate_setting(&self, iface: u8) -> rusb::Result<u8> {
+        let mut alt = [0];
+        self.read_control(
+            rusb::request_type(Direction::In, RequestType::Standard, Recipient::Interface),
+            REQUEST_GET_INTERFACE,
+            0,
+            u16::from(iface),
+            &mut alt,
+            TIMEOUT,
+        )?;
+        Ok(alt[0])
+    }
+}
+
+/// Claims `iface` and issues SET_INTERFACE for `alt`, even for alt 0 as a previous run may have
+/// left another one selected. Non-zero alts are read back, since some bootloaders otherwise keep
+/// writing to alt 0.
+pub fn select_alt_setting<H: AltSelect>(handle: &mut H, iface: u8, alt: u8) -> Result<(), Error> {
+    handle.claim_interface(iface)?;
+    handle.set_alternate_setting(iface, alt)?;
+
+    if alt != 0 {
+        match handle.alternate_setting(iface) {
+            Ok(selected) if selected != alt => return Err(Error::InvalidAlt),
+            // GET_INTERFACE is optional for devices with a single alt setting per interface.
+            Ok(_) | Err(rusb::Error::Pipe) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the number of the DFU interface of `device` whose protocol is `protocol`.
+pub fn find_dfu_interface(device: &Device<GlobalContext>, protocol: u8) -> Option<u8> {
+    let config = device.active_config_descriptor().ok()?;
+    config
+        .interfaces()
+        .flat_map(|i| i.descriptors())
+        .find(|d| {
+            (d.class_code(), d.sub_class_code(), d.protocol_code())
+                == (DFU_CLASS.0, DFU_CLASS.1, protocol)
+        })
+        .map(|d| d.interface_number())
+}
+
+/// Returns true if `device` only exposes a DFU runtime interface, i.e. still runs its application.
+pub fn in_runtime_mode(device: &Device<GlobalContext>) -> bool {
+    find_dfu_interface(device, PROTOCOL_RUNTIME).is_some()
+        && find_dfu_interface(device, PROTOCOL_DFU).is_none()
+}
+
+/// Sends DFU_DETACH to the runtime interface of `device` so it reboots into its bootloader, and
+/// resets it afterwards unless the device detaches by itself.
+pub fn detach(device: &Device<GlobalContext>) -> Result<(), Error> {
+    let iface = find_dfu_interface(device, PROTOCOL_RUNTIME).ok_or(Error::InvalidInterface)?;
+    let mut handle = device.open()?;
+    handle.claim_interface(iface)?;
+
+    let config = device.active_config_descriptor()?;
+    let descriptor = find_functional_descriptor(&handle, &config).transpose()?;
+    let timeout = descriptor.map_or(1000, |d| d.detach_timeout);
+
+    request(&handle, iface, REQUEST_DETACH, timeout, &[])?;
+
+    if !descriptor.is_some_and(|d| d.will_detach) {
+        match handle.reset() {
+            // The device may already be gone, which is what we wanted.
+            Ok(()) | Err(rusb::Error::NoDevice) | Err(rusb::Error::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer to DFU_GETSTATUS.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceStatus {
+    pub status: Status,
+    pub poll_timeout: u32,
+    pub state: State,
+    /// Index of a string describing the status, 0 if there is none.
+    pub string_index: u8,
+}
+
+fn decode_status(code: u8) -> Status {
+    match code {
+        0x00 => Status::Ok,
+        0x01 => Status::ErrTarget,
+        0x02 => Status::ErrFile,
+        0x03 => Status::ErrWrite,
+        0x04 => Status::ErrErase,
+        0x05 => Status::ErrCheckErased,
+        0x06 => Status::ErrProg,
+        0x07 => Status::ErrVerify,
+        0x08 => Status::ErrAddress,
+        0x09 => Status::ErrNotdone,
+        0x0a => Status::ErrFirmware,
+        0x0b => Status::ErrVendor,
+        0x0c => Status::ErrUsbr,
+        0x0d => Status::ErrPor,
+        0x0e => Status::ErrUnknown,
+        0x0f => Status::ErrStalledpkt,
+        other => Status::Other(other),
+    }
+}
+
+fn decode_state(code: u8) -> State {
+    match code {
+        0 => State::AppIdle,
+        1 => State::AppDetach,
+        2 => State::DfuIdle,
+        3 => State::DfuUnloadSync,
+        4 => State::DfuDnbusy,
+        5 => State::DfuDnloadIdle,
+        6 => State::DfuManifestSync,
+        7 => State::DfuManifest,
+        8 => State::DfuManifestWaitReset,
+        9 => State::DfuUploadIdle,
+        10 => State::DfuError,
+        other => State::Other(other),
+    }
+}
+
+/// Returns the name of `status` in the DFU specification, e.g. errADDRESS.
+pub fn status_name(status: Status) -> String {
+    match status {
+        Status::Ok => "OK".to_string(),
+        Status::ErrTarget => "errTARGET".to_string(),
+        Status::ErrFile => "errFILE".to_string(),
+        Status::ErrWrite => "errWRITE".to_string(),
+        Status::ErrErase => "errERASE".to_string(),
+        Status::ErrCheckErased => "errCHECK_ERASED".to_string(),
+        Status::ErrProg => "errPROG".to_string(),
+        Status::ErrVerify => "errVERIFY".to_string(),
+        Status::ErrAddress => "errADDRESS".to_string(),
+        Status::ErrNotdone => "errNOTDONE".to_string(),
+        Status::ErrFirmware => "errFIRMWARE".to_string(),
+        Status::ErrVendor => "errVENDOR".to_string(),
+        Status::ErrUsbr => "errUSBR".to_string(),
+        Status::ErrPor => "errPOR".to_string(),
+        Status::ErrUnknown => "errUNKNOWN".to_string(),
+        Status::ErrStalledpkt => "errSTALLEDPKT".to_string(),
+        Status::Other(code) => format!("status {:#04x}", code),
+    }
+}
+
+/// Returns the name of `state` in the DFU specification, e.g. dfuERROR.
+pub fn state_name(state: State) -> String {
+    match state {
+        State::AppIdle => "appIDLE".to_string(),
+        State::AppDetach => "appDETACH".to_string(),
+        State::DfuIdle => "dfuIDLE".to_string(),
+        State::DfuUnloadSync => "dfuDNLOAD-SYNC".to_string(),
+        State::DfuDnbusy => "dfuDNBUSY".to_string(),
+        State::DfuDnloadIdle => "dfuDNLOAD-IDLE".to_string(),
+        State::DfuManifestSync => "dfuMANIFEST-SYNC".to_string(),
+        State::DfuManifest => "dfuMANIFEST".to_string(),
+        State::DfuManifestWaitReset => "dfuMANIFEST-WAIT-RESET".to_string(),
+        State::DfuUploadIdle => "dfuUPLOAD-IDLE".to_string(),
+        State::DfuError => "dfuERROR".to_string(),
+        State::Other(code) => format!("state {}", code),
+    }
+}
+
+fn class_in() -> u8 {
+    rusb::request_type(Direction::In, RequestType::Class, Recipient::Interface)
+}
+
+fn class_out() -> u8 {
+    rusb::request_type(Direction::Out, RequestType::Class, Recipient::Interface)
+}
+
+/// Issues DFU_GETSTATUS.
+pub fn get_status(handle: &DeviceHandle<GlobalContext>, iface: u8) -> Result<DeviceStatus, Error> {
+    let mut buffer = [0; 6];
+    let n = handle.read_control(
+        class_in(),
+        REQUEST_GETSTATUS,
+        0,
+        u16::from(iface),
+        &mut buffer,
+        TIMEOUT,
+    )?;
+    if n < buffer.len() {
+        return Err(dfu_core::Error::ResponseTooShort {
+            got: n,
+            expected: buffer.len(),
+        }
+        .into());
+    }
+
+    Ok(DeviceStatus {
+        status: decode_status(buffer[0]),
+        poll_timeout: u32::from_le_bytes([buffer[1], buffer[2], buffer[3], 0]),
+        state: decode_state(buffer[4]),
+        string_index: buffer[5],
+    })
+}
+
+fn request(
+    handle: &DeviceHandle<GlobalContext>,
+    iface: u8,
+    request: u8,
+    value: u16,
+    data: &[u8],
+) -> Result<(), Error> {
+    handle.write_control(class_out(), request, value, u16::from(iface), data, TIMEOUT)?;
+    Ok(())
+}
+
+/// Brings the device back to dfuIDLE, clearing an error or aborting a pending transfer.
+///
+/// Fails with the state the device is left in otherwise, e.g. while it is busy or manifesting,
+/// where ABORT is not allowed.
+fn return_to_idle(handle: &DeviceHandle<GlobalContext>, iface: u8) -> Result<(), Error> {
+    match get_status(handle, iface)?.state {
+        State::DfuIdle => return Ok(()),
+        State::DfuError => request(handle, iface, REQUEST_CLRSTATUS, 0, &[])?,
+        _ => request(handle, iface, REQUEST_ABORT, 0, &[])?,
+    }
+    match get_status(handle, iface)?.state {
+        State::DfuIdle => Ok(()),
+        got => Err(dfu_core::Error::InvalidState {
+            got,
+            expected: State::DfuIdle,
+        }
+        .into()),
+    }
+}
+
+/// Polls DFU_GETSTATUS until the device is done processing a download request.
+fn wait_download_idle(handle: &DeviceHandle<GlobalContext>, iface: u8) -> Result<(), Error> {
+    loop {
+        let status = get_status(handle, iface)?;
+        match status.state {
+            State::DfuDnloadIdle | State::DfuIdle => return Ok(()),
+            State::DfuDnbusy => {
+                std::thread::sleep(Duration::from_millis(u64::from(status.poll_timeout)))
+            }
+            State::DfuError => return Err(dfu_core::Error::StatusError(status.status).into()),
+            got => {
+                return Err(dfu_core::Error::InvalidState {
+                    got,
+                    expected: State::DfuDnloadIdle,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// Reads `len` bytes back from the device, or everything until it ends the upload with a short
+/// block. DfuSe devices are read from `address`, plain DFU devices from the start of their memory.
+pub fn upload(
+    handle: &mut DeviceHandle<GlobalContext>,
+    iface: u8,
+    alt: u8,
+    address: Option<u32>,
+    len: Option<usize>,
+) -> Result<Vec<u8>, Error> {
+    select_alt_setting(handle, iface, alt)?;
+    let config = handle.device().active_config_descriptor()?;
+    let transfer_size = find_functional_descriptor(handle, &config)
+        .and_then(Result::ok)
+        .map_or(DEFAULT_TRANSFER_SIZE, |d| d.transfer_size);
+
+    return_to_idle(handle, iface)?;
+    // DfuSe reads block n at address + (n - 2) * wTransferSize, plain DFU starts at block 0.
+    let first_block = match address {
+        Some(address) => {
+            let mut command = vec![DFUSE_SET_ADDRESS];
+            command.extend_from_slice(&address.to_le_bytes());
+            request(handle, iface, REQUEST_DNLOAD, 0, &command)?;
+            wait_download_idle(handle, iface)?;
+            request(handle, iface, REQUEST_ABORT, 0, &[])?;
+            2
+        }
+        None => 0,
+    };
+
+    let mut data = vec![];
+    for block in first_block.. {
+        let chunk = match len {
+            Some(len) if data.len() == len => break,
+            Some(len) => (len - data.len()).min(usize::from(transfer_size)),
+            None => usize::from(transfer_size),
+        };
+        let read = data.len();
+        data.resize(read + chunk, 0);
+        let n = handle.read_control(
+            class_in(),
+            REQUEST_UPLOAD,
+            block,
+            u16::from(iface),
+            &mut data[read..],
+            TIMEOUT,
+        )?;
+        data.truncate(read + n);
+        // A short block ends the upload.
+        if n < chunk {
+            break;
+        }
+    }
+
+    request(handle, iface, REQUEST_ABORT, 0, &[])?;
+    Ok(data)
+}
+
+/// Brings a device left in another state by an interrupted run back to dfuIDLE, returning the
+/// state it was found in if it had to.
+pub fn recover_idle(
+    handle: &mut DeviceHandle<GlobalContext>,
+    iface: u8,
+    alt: u8,
+) -> Result<Option<State>, Error> {
+    select_alt_setting(handle, iface, alt)?;
+    let state = get_status(handle, iface)?.state;
+    if state == State::DfuIdle {
+        return Ok(None);
+    }
+    return_to_idle(handle, iface)?;
+    Ok(Some(state))
+}
+
+/// Erases the page of a DfuSe device containing `address`, or its whole memory without one, and
+/// waits for the erase to complete.
+pub fn erase(
+    handle: &mut DeviceHandle<GlobalContext>,
+    iface: u8,
+    alt: u8,
+    address: Option<u32>,
+) -> Result<(), Error> {
+    select_alt_setting(handle, iface, alt)?;
+    return_to_idle(handle, iface)?;
+
+    let mut command = vec![DFUSE_ERASE];
+    if let Some(address) = address {
+        command.extend_from_slice(&address.to_le_bytes());
+    }
+    request(handle, iface, REQUEST_DNLOAD, 0, &command)?;
+    // The erase itself runs while the device reports dfuDNBUSY, which may take several seconds.
+    wait_download_idle(handle, iface)?;
+    request(handle, iface, REQUEST_ABORT, 0, &[])?;
+    handle.release_interface(iface)?;
+    Ok(())
+}
+
+/// Opens `handle` for DFU on the given interface and alt setting.
+pub fn open(
+    mut handle: DeviceHandle<GlobalContext>,
+    iface: u8,
+    alt: u8,
+    transfer: Transfer,
+) -> Result<Dfu, Error> {
+    select_alt_setting(&mut handle, iface, alt)?;
+
+    let device = handle.device();
+    let device_descriptor = device.device_descriptor()?;
+    let languages = handle.read_languages(TIMEOUT)?;
+    let lang = languages.first().ok_or(Error::MissingLanguage)?;
+
+    for index in 0..device_descriptor.num_configurations() {
+        let config_descriptor = device.config_descriptor(index)?;
+
+        let interface = config_descriptor
+            .interfaces()
+            .find(|x| x.number() == iface)
+            .ok_or(Error::InvalidInterface)?;
+        let iface_desc = interface
+            .descriptors()
+            .find(|x| x.setting_number() == alt)
+            .ok_or(Error::InvalidAlt)?;
+        let interface_string = handle.read_interface_string(*lang, &iface_desc, TIMEOUT)?;
+
+        let (rest, memory_layout) = interface_string
+            .rsplit_once('/')
+            .ok_or(Error::InvalidInterfaceString)?;
+        let memory_layout = MemoryLayout::try_from(memory_layout).map_err(Error::MemoryLayout)?;
+        let (_rest, address) = rest.rsplit_once('/').ok_or(Error::InvalidInterfaceString)?;
+        let address = address
+            .strip_prefix("0x")
+            .and_then(|s| u32::from_str_radix(s, 16).ok())
+            .ok_or(Error::InvalidAddress)?;
+
+        if let Some(mut functional_descriptor) =
+            find_functional_descriptor(&handle, &config_descriptor).transpose()?
+        {
+            if let Some(size) = transfer.size {
+                functional_descriptor.transfer_size = size;
+            }
+            let io = DfuDevice {
+                usb: RefCell::new(handle),
+                memory_layout,
+                timeout: transfer.timeout,
+                iface: u16::from(iface),
+                functional_descriptor,
+            };
+
+            return Ok(dfu_core::sync::DfuSync::new(io, address));
+        }
+    }
+
+    Err(Error::NoDfuCapableDeviceFound)
+}
+
+/// Returns the DFU functional descriptor of the device behind `handle`, if it has one.
+pub fn functional_descriptor(
+    handle: &DeviceHandle<GlobalContext>,
+) -> Option<Result<FunctionalDescriptor, Error>> {
+    match handle.device().active_config_descriptor() {
+        Ok(config) => find_functional_descriptor(handle, &config),
+        Err(e) => Some(Err(e.into())),
+    }
+}
+
+fn find_functional_descriptor(
+    handle: &DeviceHandle<GlobalContext>,
+    config: &rusb::ConfigDescriptor,
+) -> Option<Result<FunctionalDescriptor, Error>> {
+    if let Some(descriptor) = FunctionalDescriptor::from_bytes(config.extra()) {
+        return Some(descriptor.map_err(Into::into));
+    }
+
+    for if_desc in config.interfaces().flat_map(|x| x.descriptors()) {
+        if let Some(descriptor) = FunctionalDescriptor::from_bytes(if_desc.extra()) {
+            return Some(descriptor.map_err(Into::into));
+        }
+    }
+
+    let mut buffer = [0x00; 9];
+    match handle.read_control(
+        LIBUSB_ENDPOINT_IN,
+        LIBUSB_REQUEST_GET_DESCRIPTOR,
+        0x2100,
+        0,
+        &mut buffer,
+        TIMEOUT,
+    ) {
+        Ok(n) => FunctionalDescriptor::from_bytes(&buffer[..n]).map(|d| d.map_err(Into::into)),
+        Err(err) => Some(Err(err.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the requests and answers GET_INTERFACE with `current`.
+    #[derive(Default)]
+    struct FakeHandle {
+        calls: Vec<String>,
+        current: Option<u8>,
+        ignores_set_interface: bool,
+    }
+
+    impl AltSelect for FakeHandle {
+        fn claim_interface(&mut self, iface: u8) -> rusb::Result<()> {
+            self.calls.push(format!("claim {}", iface));
+            Ok(())
+        }
+
+        fn set_alternate_setting(&mut self, iface: u8, alt: u8) -> rusb::Result<()> {
+            self.calls.push(format!("set {} {}", iface, alt));
+            if !self.ignores_set_interface {
+                self.current = Some(alt);
+            }
+            Ok(())
+        }
+
+        fn alternate_setting(&self, _: u8) -> rusb::Result<u8> {
+            self.current.ok_or(rusb::Error::Pipe)
+        }
+    }
+
+    #[test]
+    fn names_statuses_and_states() {
+        assert_eq!(status_name(decode_status(0x08)), "errADDRESS");
+        assert_eq!(status_name(decode_status(0x42)), "status 0x42");
+        assert_eq!(state_name(decode_state(10)), "dfuERROR");
+        assert_eq!(state_name(decode_state(3)), "dfuDNLOAD-SYNC");
+    }
+
+    #[test]
+    fn sets_non_zero_alt() {
+        let mut handle = FakeHandle::default();
+        select_alt_setting(&mut handle, 0, 1).unwrap();
+        assert_eq!(handle.calls, vec!["claim 0", "set 0 1"]);
+    }
+
+    #[test]
+    fn sets_alt_zero_too() {
+        let mut handle = FakeHandle::default();
+        select_alt_setting(&mut handle, 2, 0).unwrap();
+        assert_eq!(handle.calls, vec!["claim 2", "set 2 0"]);
+    }
+
+    #[test]
+    fn fails_when_the_alt_did_not_change() {
+        let mut handle = FakeHandle {
+            current: Some(0),
+            ignores_set_interface: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            select_alt_setting(&mut handle, 0, 2),
+            Err(Error::InvalidAlt)
+        ));
+    }
+
+    #[test]
+    fn accepts_devices_without_get_interface() {
+        let mut handle = FakeHandle {
+            ignores_set_interface: true,
+            ..Default::default()
+        };
+        select_alt_setting(&mut handle, 0, 1).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+//! Ctrl-C handling while waiting for a device indefinitely.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Ctrl-C is caught as long as this lives, and ends the process right away again afterwards.
+pub struct Guard(());
+
+/// Records SIGINT instead of being killed by it, so the wait can end with a proper report.
+#[cfg(unix)]
+pub fn catch() -> Guard {
+    extern "C" fn handle(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    Guard(())
+}
+
+/// Ctrl-C keeps ending the process right away where we can't catch it.
+#[cfg(not(unix))]
+pub fn catch() -> Guard {
+    Guard(())
+}
+
+#[cfg(unix)]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // SAFETY: restores the default disposition of SIGINT.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
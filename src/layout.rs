@@ -0,0 +1,413 @@
+//! Parser for the DfuSe memory layout strings found in the alt setting interface descriptors,
+//! e.g. `@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg`.
+
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum LayoutError {
+    /// The string does not start with `@`.
+    MissingName,
+    /// A segment has no base address, or the address is not hex.
+    InvalidAddress(String),
+    /// A sector description is not of the form `NN*SSSut`.
+    InvalidSector(String),
+    /// The image starts outside of every segment.
+    OutOfRange { address: u32 },
+    /// The image runs into a sector that is not writable, or past the end of the segment.
+    NotWritable { address: u32 },
+    /// The image starts in the middle of an erasable sector.
+    Unaligned { address: u32, sector_start: u32 },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::MissingName => write!(f, "layout string does not start with '@'"),
+            LayoutError::InvalidAddress(s) => write!(f, "invalid segment address {:?}", s),
+            LayoutError::InvalidSector(s) => write!(f, "invalid sector description {:?}", s),
+            LayoutError::OutOfRange { address } => {
+                write!(f, "address {:#010x} is not in any memory segment", address)
+            }
+            LayoutError::NotWritable { address } => {
+                write!(f, "address {:#010x} is not writable", address)
+            }
+            LayoutError::Unaligned {
+                address,
+                sector_start,
+            } => write!(
+                f,
+                "address {:#010x} is not aligned to its sector at {:#010x}",
+                address, sector_start
+            ),
+        }
+    }
+}
+
+/// Access rights of a group of sectors, encoded as the letters `a` to `g`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SectorType(u8);
+
+impl SectorType {
+    pub fn readable(&self) -> bool {
+        self.0 & 0b001 != 0
+    }
+
+    pub fn erasable(&self) -> bool {
+        self.0 & 0b010 != 0
+    }
+
+    pub fn writable(&self) -> bool {
+        self.0 & 0b100 != 0
+    }
+}
+
+impl fmt::Display for SectorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.readable(), 'r'),
+            flag(self.erasable(), 'e'),
+            flag(self.writable(), 'w')
+        )
+    }
+}
+
+/// `count` consecutive sectors of `size` bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sectors {
+    pub count: u32,
+    pub size: u32,
+    pub kind: SectorType,
+}
+
+/// A contiguous memory range starting at `base`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub base: u32,
+    pub sectors: Vec<Sectors>,
+}
+
+impl Segment {
+    /// Total length of the segment in bytes.
+    pub fn len(&self) -> u64 {
+        self.sectors
+            .iter()
+            .map(|s| u64::from(s.count) * u64::from(s.size))
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over every single sector as `(start address, size, type)`.
+    fn each_sector(&self) -> impl Iterator<Item = (u64, u32, SectorType)> + '_ {
+        self.sectors
+            .iter()
+            .flat_map(|s| (0..s.count).map(move |_| (s.size, s.kind)))
+            .scan(u64::from(self.base), |address, (size, kind)| {
+                let start = *address;
+                *address += u64::from(size);
+                Some((start, size, kind))
+            })
+    }
+}
+
+/// The memory layout of one alt setting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub name: String,
+    pub segments: Vec<Segment>,
+}
+
+impl Layout {
+    /// Parses a DfuSe interface string.
+    pub fn parse(s: &str) -> Result<Self, LayoutError> {
+        let s = s.strip_prefix('@').ok_or(LayoutError::MissingName)?;
+        let mut parts = s.split('/');
+        let name = parts.next().unwrap_or_default().trim().to_string();
+
+        let mut segments = vec![];
+        while let Some(address) = parts.next() {
+            let address = address.trim();
+            let base = address
+                .strip_prefix("0x")
+                .or_else(|| address.strip_prefix("0X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| LayoutError::InvalidAddress(address.to_string()))?;
+            let sectors = parts
+                .next()
+                .ok_or_else(|| LayoutError::InvalidSector(String::new()))?
+                .split(',')
+                .map(parse_sectors)
+                .collect::<Result<_, _>>()?;
+            segments.push(Segment { base, sectors });
+        }
+
+        Ok(Layout { name, segments })
+    }
+
+    /// Checks that `len` bytes written at `address` only touch writable sectors, possibly of
+    /// several adjacent segments such as the banks of a dual bank flash, and, when the first
+    /// sector gets erased, that the image starts on its boundary.
+    pub fn check_fits(&self, address: u32, len: usize) -> Result<(), LayoutError> {
+        let start = u64::from(address);
+        let end = start + len as u64;
+
+        let mut sectors: Vec<_> = self
+            .segments
+            .iter()
+            .flat_map(Segment::each_sector)
+            .collect();
+        sectors.sort_by_key(|(sector_start, _, _)| *sector_start);
+        let mut sectors = sectors
+            .into_iter()
+            .skip_while(|(sector_start, size, _)| sector_start + u64::from(*size) <= start)
+            .peekable();
+        if sectors
+            .peek()
+            .is_none_or(|(sector_start, _, _)| *sector_start > start)
+        {
+            return Err(LayoutError::OutOfRange { address });
+        }
+
+        // End of the writable memory checked so far.
+        let mut covered = start;
+        for (sector_start, size, kind) in sectors {
+            if covered >= end || sector_start > covered {
+                break;
+            }
+            if !kind.writable() {
+                return Err(LayoutError::NotWritable {
+                    address: covered as u32,
+                });
+            }
+            if kind.erasable() && sector_start < start {
+                return Err(LayoutError::Unaligned {
+                    address,
+                    sector_start: sector_start as u32,
+                });
+            }
+            covered = sector_start + u64::from(size);
+        }
+
+        if covered < end {
+            return Err(LayoutError::NotWritable {
+                address: covered as u32,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for segment in &self.segments {
+            write!(f, "\n        {:#010x}", segment.base)?;
+            for s in &segment.sectors {
+                write!(f, " {}x{}B {}", s.count, s.size, s.kind)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a single `NN*SSSut` sector group, where `u` is the unit (` `, `K` or `M`) and `t` the
+/// type (`a` to `g`).
+fn parse_sectors(s: &str) -> Result<Sectors, LayoutError> {
+    let invalid = || LayoutError::InvalidSector(s.to_string());
+    let (count, rest) = s.trim().split_once('*').ok_or_else(invalid)?;
+    let count = count.trim().parse().map_err(|_| invalid())?;
+
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let size: u32 = rest[..digits].parse().map_err(|_| invalid())?;
+    let mut suffix = rest[digits..].chars();
+    let (multiplier, kind) = match (suffix.next(), suffix.next(), suffix.next()) {
+        (Some('K'), Some(kind), None) => (1024, kind),
+        (Some('M'), Some(kind), None) => (1024 * 1024, kind),
+        (Some(' '), Some(kind), None) | (Some(kind), None, None) => (1, kind),
+        _ => return Err(invalid()),
+    };
+    let kind = match kind {
+        'a'..='g' => SectorType(kind as u8 - b'a' + 1),
+        _ => return Err(invalid()),
+    };
+
+    Ok(Sectors {
+        count,
+        size: size.checked_mul(multiplier).ok_or_else(invalid)?,
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stm32f4_internal_flash() {
+        let layout =
+            Layout::parse("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+        assert_eq!(layout.name, "Internal Flash");
+        assert_eq!(layout.segments.len(), 1);
+        let segment = &layout.segments[0];
+        assert_eq!(segment.base, 0x0800_0000);
+        assert_eq!(
+            segment.sectors,
+            vec![
+                Sectors {
+                    count: 4,
+                    size: 16 * 1024,
+                    kind: SectorType(7)
+                },
+                Sectors {
+                    count: 1,
+                    size: 64 * 1024,
+                    kind: SectorType(7)
+                },
+                Sectors {
+                    count: 7,
+                    size: 128 * 1024,
+                    kind: SectorType(7)
+                },
+            ]
+        );
+        assert_eq!(segment.len(), 1024 * 1024);
+    }
+
+    #[test]
+    fn parses_stm32f1_pages() {
+        let layout = Layout::parse("@Internal Flash  /0x08000000/128*002Kg").unwrap();
+        assert_eq!(layout.segments[0].len(), 256 * 1024);
+        assert_eq!(layout.segments[0].sectors[0].size, 2048);
+    }
+
+    #[test]
+    fn parses_byte_sized_sectors() {
+        let layout = Layout::parse("@Option Bytes  /0x1FFFC000/01*016 e").unwrap();
+        let sectors = &layout.segments[0].sectors[0];
+        assert_eq!(sectors.size, 16);
+        assert!(sectors.kind.readable());
+        assert!(!sectors.kind.erasable());
+        assert!(sectors.kind.writable());
+
+        let layout = Layout::parse("@OTP Memory /0x1FFF7800/01*512 e,01*016 e").unwrap();
+        assert_eq!(layout.segments[0].len(), 528);
+    }
+
+    #[test]
+    fn parses_megabyte_sectors_and_read_only() {
+        let layout =
+            Layout::parse("@Device Feature/0xFFFF0000/01*004 e/0x08000000/02*001Ma").unwrap();
+        assert_eq!(layout.segments.len(), 2);
+        assert_eq!(layout.segments[1].base, 0x0800_0000);
+        assert_eq!(layout.segments[1].sectors[0].size, 1024 * 1024);
+        assert!(!layout.segments[1].sectors[0].kind.writable());
+    }
+
+    #[test]
+    fn parses_multiple_ranges() {
+        let layout = Layout::parse(
+            "@Internal Flash   /0x08000000/04*032Kg,01*128Kg,03*256Kg/0x08100000/04*032Kg,01*128Kg,03*256Kg",
+        )
+        .unwrap();
+        assert_eq!(layout.segments.len(), 2);
+        assert_eq!(layout.segments[0].len(), 1024 * 1024);
+        assert_eq!(layout.segments[1].base, 0x0810_0000);
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        assert_eq!(
+            Layout::parse("Internal Flash/0x08000000/04*016Kg"),
+            Err(LayoutError::MissingName)
+        );
+        assert_eq!(
+            Layout::parse("@Internal Flash/08000000/04*016Kg"),
+            Err(LayoutError::InvalidAddress("08000000".to_string()))
+        );
+        assert_eq!(
+            Layout::parse("@Internal Flash/0x08000000/04x016Kg"),
+            Err(LayoutError::InvalidSector("04x016Kg".to_string()))
+        );
+        assert_eq!(
+            Layout::parse("@Internal Flash/0x08000000/04*016Kz"),
+            Err(LayoutError::InvalidSector("04*016Kz".to_string()))
+        );
+        assert!(Layout::parse("@Internal Flash/0x08000000").is_err());
+    }
+
+    #[test]
+    fn checks_image_fits() {
+        let layout =
+            Layout::parse("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+        assert_eq!(layout.check_fits(0x0800_0000, 1024 * 1024), Ok(()));
+        assert_eq!(layout.check_fits(0x0800_4000, 100), Ok(()));
+        assert_eq!(
+            layout.check_fits(0x0800_0000, 1024 * 1024 + 1),
+            Err(LayoutError::NotWritable {
+                address: 0x0810_0000
+            })
+        );
+        assert_eq!(
+            layout.check_fits(0x2000_0000, 4),
+            Err(LayoutError::OutOfRange {
+                address: 0x2000_0000
+            })
+        );
+        assert_eq!(
+            layout.check_fits(0x0800_0100, 4),
+            Err(LayoutError::Unaligned {
+                address: 0x0800_0100,
+                sector_start: 0x0800_0000
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_read_only_sectors() {
+        let layout = Layout::parse("@Internal Flash  /0x08000000/01*016Ka,03*016Kg").unwrap();
+        assert_eq!(
+            layout.check_fits(0x0800_0000, 4),
+            Err(LayoutError::NotWritable {
+                address: 0x0800_0000
+            })
+        );
+        assert_eq!(layout.check_fits(0x0800_4000, 4), Ok(()));
+    }
+
+    #[test]
+    fn spans_adjacent_segments() {
+        let layout = Layout::parse(
+            "@Internal Flash   /0x08000000/04*032Kg,01*128Kg,03*256Kg/0x08100000/04*032Kg,01*128Kg,03*256Kg",
+        )
+        .unwrap();
+        assert_eq!(layout.check_fits(0x0800_0000, 0x18_0000), Ok(()));
+        assert_eq!(
+            layout.check_fits(0x0800_0000, 0x20_0001),
+            Err(LayoutError::NotWritable {
+                address: 0x0820_0000
+            })
+        );
+
+        // A hole between the segments is not memory.
+        let layout = Layout::parse("@Flash /0x08000000/02*016Kg/0x08010000/02*016Kg").unwrap();
+        assert_eq!(
+            layout.check_fits(0x0800_0000, 0x9000),
+            Err(LayoutError::NotWritable {
+                address: 0x0800_8000
+            })
+        );
+    }
+
+    #[test]
+    fn allows_unaligned_writes_to_non_erasable_sectors() {
+        let layout = Layout::parse("@Option Bytes  /0x1FFFC000/01*016 e").unwrap();
+        assert_eq!(layout.check_fits(0x1FFF_C008, 8), Ok(()));
+    }
+}
@@ -0,0 +1,140 @@
+//! Flashing of firmware images over USB DFU, the library behind `cargo dfu`.
+
+pub mod checksum;
+#[cfg(feature = "probe-rs")]
+pub mod chip_db;
+pub mod config;
+pub mod device;
+pub mod dfu;
+pub mod layout;
+pub mod progress;
+pub mod retries;
+pub mod selftest;
+pub mod srec;
+pub mod utils;
+pub mod warnings;
+
+pub use device::{find_device, find_devices, DeviceSelector};
+pub use utils::{elf_to_bin, UtilError};
+
+use dfu::Transfer;
+use rusb::{DeviceHandle, GlobalContext};
+use std::ops::Range;
+use std::path::PathBuf;
+use utils::{alt_layout, check_flash_region, flash_bin, image_to_bin, AddressSource, Image};
+use warnings::Warnings;
+
+/// What to erase before the download, which only DfuSe devices support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// The whole flash.
+    Mass,
+    /// The page containing the address.
+    Page(u32),
+}
+
+/// How to flash an image, see [`flash`].
+#[derive(Debug, Clone)]
+pub struct FlashOptions {
+    pub interface: u8,
+    pub alt: u8,
+    /// Address to write the image at instead of the one it is linked at.
+    pub address: Option<u32>,
+    pub address_source: AddressSource,
+    /// Byte to fill the gaps between the records of an Intel HEX file with.
+    pub hex_fill: u8,
+    /// Byte to fill the gaps between the segments of an ELF file with.
+    pub elf_fill: u8,
+    /// Timeout and block size of the download.
+    pub transfer: Transfer,
+    /// Flash of the chip, e.g. from the probe-rs chip database, which the image must fit. Raw
+    /// binaries go to its start if the device describes no memory.
+    pub flash_region: Option<Range<u64>>,
+    pub erase: Option<Erase>,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            interface: 0,
+            alt: 0,
+            address: None,
+            address_source: AddressSource::Auto,
+            hex_fill: 0xff,
+            elf_fill: 0x00,
+            transfer: Transfer::default(),
+            flash_region: None,
+            erase: None,
+        }
+    }
+}
+
+/// Flashes the ELF, Intel HEX or raw binary image at `path` to the device picked by `selector`.
+///
+/// The image is checked against the memory layout of DfuSe devices first, and raw binaries are
+/// written at the start of that memory unless an address is given.
+pub fn flash(
+    path: PathBuf,
+    selector: &DeviceSelector,
+    options: &FlashOptions,
+) -> Result<(), UtilError> {
+    let handle = find_device(selector)?.ok_or(UtilError::Usb(rusb::Error::NoDevice))?;
+    let image = image_to_bin(
+        path,
+        options.address_source,
+        options.hex_fill,
+        options.elf_fill,
+        &mut Warnings::default(),
+    )?;
+
+    flash_image(&handle, &image, options, |_| {}).map(drop)
+}
+
+/// Flashes `image` to the device behind `handle`, see [`flash`], calling `progress` with the
+/// number of bytes written by every transfer. Returns the address the image was written at.
+pub fn flash_image(
+    handle: &DeviceHandle<GlobalContext>,
+    image: &Image,
+    options: &FlashOptions,
+    progress: impl FnMut(usize) + 'static,
+) -> Result<u32, UtilError> {
+    let layout = alt_layout(handle, options.interface, options.alt);
+    let address = options
+        .address
+        .or(image.address)
+        .or_else(|| Some(layout.as_ref()?.segments.first()?.base))
+        .or_else(|| u32::try_from(options.flash_region.as_ref()?.start).ok())
+        .ok_or(UtilError::MissingAddress)?;
+    if let Some(layout) = &layout {
+        layout
+            .check_fits(address, image.data.len())
+            .map_err(|e| UtilError::Layout(layout.name.clone(), e))?;
+    }
+    if let Some(flash) = &options.flash_region {
+        check_flash_region(address, image.data.len(), flash)?;
+    }
+
+    if let Some(erase) = options.erase {
+        // Only DfuSe devices describe their memory, and only they know the erase command.
+        if layout.is_none() {
+            return Err(UtilError::NotDfuSe);
+        }
+        let page = match erase {
+            Erase::Mass => None,
+            Erase::Page(address) => Some(address),
+        };
+        let mut eraser = handle.device().open().map_err(UtilError::Usb)?;
+        dfu::erase(&mut eraser, options.interface, options.alt, page).map_err(UtilError::Dfu)?;
+    }
+
+    flash_bin(
+        &image.data,
+        address,
+        &handle.device(),
+        options.interface,
+        options.alt,
+        options.transfer,
+        progress,
+    )?;
+    Ok(address)
+}
@@ -1,14 +1,34 @@
-mod utils;
+mod args;
+mod interrupt;
+#[macro_use]
+mod report;
 
-use crate::utils::{elf_to_bin, flash_bin, vendor_map};
+use crate::args::cargo_build_args;
+use cargo_dfu::checksum::Hash;
+use cargo_dfu::layout::Layout;
+use cargo_dfu::retries::Retries;
+use cargo_dfu::utils::{
+    alt_layout, alt_settings, check_flash_region, check_image, check_interface_free, device_path,
+    find_alt_setting, image_to_bin, product_name, serial_number, vendor_map, verify_bin,
+    AddressSource, Image, PortPath, UtilError, VerifyMode,
+};
+use cargo_dfu::warnings::Warnings;
+use cargo_dfu::{
+    config, device, dfu, progress, selftest, srec, DeviceSelector, Erase, FlashOptions,
+};
 use colored::Colorize;
-use rusb::{open_device_with_vid_pid, GlobalContext};
+use rusb::GlobalContext;
 
 use clap::Parser;
-use std::path::PathBuf;
+use retry::OperationResult;
+use std::io::IsTerminal;
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::Instant;
-// use structopt::StructOpt;
+use std::time::{Duration, Instant};
+
+/// How often `--wait` tells that it is still waiting.
+const STILL_WAITING_INTERVAL: Duration = Duration::from_secs(10);
 
 fn main() {
     // Initialize the logging backend.
@@ -16,174 +36,1262 @@ fn main() {
 
     // Get commandline options.
     // Skip the first arg which is the calling application name.
-    let opt = Opt::parse_from(std::env::args().skip(1));
+    let mut opt = match Opt::try_parse_from(std::env::args().skip(1)) {
+        Ok(opt) => opt,
+        Err(e) => usage_error(e),
+    };
+
+    if opt.format == Format::Json {
+        report::enable();
+        opt.color = ColorChoice::Never;
+    }
+
+    match opt.color {
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+        ColorChoice::Auto => {
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+            if no_color || !std::io::stdout().is_terminal() {
+                colored::control::set_override(false);
+            }
+        }
+    }
+
+    // Our own arguments are stripped from the cargo command line by position.
+    if opt.phase.is_some()
+        && !std::env::args()
+            .nth(2)
+            .is_some_and(|arg| args::PHASES.contains(&arg.as_str()))
+    {
+        report::fail(
+            report::Exit::Error,
+            "phase",
+            "the build or flash phase must be the first argument".to_string(),
+        );
+    }
+    if opt.phase == Some(Phase::Build) && opt.firmware.is_some() {
+        report::fail(
+            report::Exit::Error,
+            "phase",
+            "there is nothing to build with --firmware".to_string(),
+        );
+    }
+    if opt.phase == Some(Phase::Flash) {
+        opt.no_build = true;
+    }
+
+    opt.chips = match config::chip_map(vendor_map(), project_dir(&opt)) {
+        Ok(chips) => chips,
+        Err(e) => report::fail(report::Exit::Error, "config", e.to_string()),
+    };
+
+    let retries = Retries::new(
+        opt.max_retries_per_phase,
+        Duration::from_millis(opt.delay),
+        opt.deadline.map(Duration::from_secs),
+    );
 
     if opt.list_chips {
-        for vendor in vendor_map() {
-            println!("{}", vendor.0);
+        for vendor in &opt.chips {
+            status!("{}", vendor.0);
+        }
+        let chips = opt
+            .chips
+            .iter()
+            .map(|(name, ids)| report::ChipReport {
+                name: name.clone(),
+                ids: ids
+                    .iter()
+                    .map(|&(vid, pid)| report::UsbIds { vid, pid })
+                    .collect(),
+            })
+            .collect();
+        report::update(|r| r.chips = Some(chips));
+        report::finish(&Warnings::default())
+    }
+
+    if opt.list_devices {
+        list_devices(&opt);
+        report::finish(&Warnings::default())
+    }
+
+    if opt.list_alts {
+        let Some(d) = wait_for_device(&opt, &retries) else {
+            device_not_found(&opt)
+        };
+
+        report_device(&d);
+        let alts = alt_settings(&d).unwrap_or_else(|e| {
+            exit_with_error(
+                report::Exit::NoDevice,
+                "reading the interface descriptors",
+                UtilError::Usb(e),
+            )
+        });
+        for alt in &alts {
+            status!(
+                "    {} interface {} alt {}: {}",
+                "Found".green().bold(),
+                alt.interface,
+                alt.alt,
+                Layout::parse(&alt.name)
+                    .map(|layout| layout.to_string())
+                    .unwrap_or_else(|_| alt.name.clone())
+            );
+        }
+        let alts = alts
+            .into_iter()
+            .map(|alt| report::AltReport {
+                interface: alt.interface,
+                alt: alt.alt,
+                name: alt.name,
+            })
+            .collect();
+        report::update(|r| r.alts = Some(alts));
+        report::finish(&Warnings::default())
+    }
+
+    if let Some(output) = &opt.upload {
+        let Some(mut d) = wait_for_device(&opt, &retries) else {
+            device_not_found(&opt)
+        };
+        let (interface, alt) = interface_or_exit(select_interface(&opt, &d));
+        if let Err(rusb::Error::Busy) = check_interface_free(&mut d, interface) {
+            interface_busy()
+        }
+        report_device(&d);
+        if opt.verbose {
+            dump_descriptors(&d);
+        }
+
+        // DfuSe devices are read from the start of the selected memory unless told otherwise.
+        let address = opt
+            .address
+            .or_else(|| Some(alt_layout(&d, interface, alt)?.segments.first()?.base));
+        let data = dfu::upload(
+            &mut d,
+            interface,
+            alt,
+            address,
+            opt.length.map(|l| l as usize),
+        )
+        .unwrap_or_else(|e| exit_with_error(report::Exit::Flash, "uploading", UtilError::Dfu(e)));
+        if let Err(e) = std::fs::write(output, &data) {
+            report::fail(
+                report::Exit::Error,
+                "output",
+                format!("writing {:?}: {}", output, e),
+            );
+        }
+        status!(
+            "    {} {} bytes to {:?}",
+            "Read".green().bold(),
+            data.len(),
+            output
+        );
+        report::update(|r| {
+            r.size = Some(data.len());
+            r.address = address;
+        });
+        report::finish(&Warnings::default())
+    }
+
+    // With --search-before-build the device is searched for while cargo builds, so a manual
+    // button-hold lines up with the retries. A failing build exits before anything is flashed.
+    let (path, found) = std::thread::scope(|s| {
+        let search = opt
+            .search_before_build
+            .then(|| s.spawn(|| wait_for_device(&opt, &retries)));
+        let path = match &opt.firmware {
+            Some(firmware) => firmware.clone(),
+            None => build(&opt),
+        };
+        let found = search.map(|search| search.join().expect("the device search panicked"));
+        (path, found)
+    });
+
+    // The artifact only exists after the build, so check it here rather than while resolving.
+    if !path.is_file() && opt.firmware.is_some() {
+        report::fail(
+            report::Exit::Error,
+            "firmware",
+            format!("firmware file {:?} not found", path),
+        );
+    } else if !path.is_file() {
+        report::fail(
+            report::Exit::Build,
+            "artifact",
+            format!(
+                "artifact {:?} does not exist, check the path or build it first",
+                path
+            ),
+        );
+    }
+    report::update(|r| r.artifact = Some(path.clone()));
+
+    if opt.phase == Some(Phase::Build) {
+        status!("    {} {}", "Built".green().bold(), path.display());
+        report::finish(&Warnings::default())
+    }
+
+    let mut warnings = Warnings::default();
+
+    if let Some(output) = &opt.output {
+        let Image {
+            data: binary,
+            address: image_address,
+            ..
+        } = image_to_bin(
+            path,
+            opt.address_source,
+            opt.hex_fill,
+            opt.fill_byte,
+            &mut warnings,
+        )
+        .unwrap_or_else(|e| exit_with_error(report::Exit::Error, "reading the image", e));
+        let flash = flash_region(&opt);
+        let Some(address) = opt
+            .address
+            .or(image_address)
+            .or_else(|| u32::try_from(flash.as_ref()?.start).ok())
+        else {
+            exit_with_error(
+                report::Exit::Error,
+                "converting the image",
+                UtilError::MissingAddress,
+            )
+        };
+        if let Some(flash) = &flash {
+            if let Err(e) = check_flash_region(address, binary.len(), flash) {
+                exit_with_error(report::Exit::Error, "checking the image", e);
+            }
+        }
+        if let Err(e) = write_image(output, &binary, address) {
+            report::fail(
+                report::Exit::Error,
+                "output",
+                format!("writing {:?}: {}", output, e),
+            );
+        }
+        status!("    {} {:?}", "Wrote".green().bold(), output);
+        report::update(|r| {
+            r.size = Some(binary.len());
+            r.address = Some(address);
+        });
+        report::finish(&warnings)
+    }
+
+    if opt.dry_run {
+        let image = image_to_bin(
+            path,
+            opt.address_source,
+            opt.hex_fill,
+            opt.fill_byte,
+            &mut warnings,
+        )
+        .unwrap_or_else(|e| exit_with_error(report::Exit::Error, "reading the image", e));
+        sanity_check(&opt, &image, &mut warnings);
+        let flash = flash_region(&opt);
+        let address = opt
+            .address
+            .or(image.address)
+            .or_else(|| u32::try_from(flash.as_ref()?.start).ok());
+        if let Some((address, flash)) = address.zip(flash) {
+            if let Err(e) = check_flash_region(address, image.data.len(), &flash) {
+                exit_with_error(report::Exit::Error, "checking the image", e);
+            }
+        }
+        status!(
+            "    {} {} bytes at {} in {} region(s), nothing flashed",
+            "Dry run".green().bold(),
+            image.data.len(),
+            address.map_or("start of the selected memory".to_string(), |a| format!(
+                "{:#010x}",
+                a
+            )),
+            image.regions
+        );
+        report::update(|r| {
+            r.size = Some(image.data.len());
+            r.address = address;
+        });
+        print_checksums(&opt, &image.data);
+        report::finish(&warnings)
+    }
+
+    if opt.all {
+        flash_all(&opt, path, warnings)
+    }
+
+    let found = found.unwrap_or_else(|| wait_for_device(&opt, &retries));
+    let Some(mut d) = found else {
+        device_not_found(&opt)
+    };
+
+    let (interface, alt) = interface_or_exit(select_interface(&opt, &d));
+
+    if let Err(rusb::Error::Busy) = check_interface_free(&mut d, interface) {
+        interface_busy()
+    }
+
+    report_device(&d);
+    if opt.verbose {
+        dump_descriptors(&d);
+    }
+
+    let serial_number = serial_number(&d);
+    if serial_number.is_none() {
+        warnings.push("the device has no serial number".to_string());
+    }
+
+    match dfu::functional_descriptor(&d) {
+        Some(Ok(descriptor)) => {
+            if !descriptor.can_download {
+                warnings.push("the device does not advertise download capability".to_string());
+            }
+            if opt.verify.is_some() && !descriptor.can_upload {
+                warnings.push("the device does not advertise upload capability".to_string());
+            }
+            if ![(1, 0x00), (1, 0x01), (1, 0x10), (1, 0x1a)].contains(&descriptor.dfu_version) {
+                warnings.push(format!(
+                    "unknown DFU version {:x}.{:02x}",
+                    descriptor.dfu_version.0, descriptor.dfu_version.1
+                ));
+            }
+        }
+        Some(Err(e)) => warnings.push(format!("invalid DFU functional descriptor: {}", e)),
+        None => warnings.push("the device has no DFU functional descriptor".to_string()),
+    }
+
+    status!("    {} {:?}", "Flashing".green().bold(), path);
+
+    let image = image_to_bin(
+        path,
+        opt.address_source,
+        opt.hex_fill,
+        opt.fill_byte,
+        &mut warnings,
+    )
+    .unwrap_or_else(|e| exit_with_error(report::Exit::Error, "reading the image", e));
+    sanity_check(&opt, &image, &mut warnings);
+    report::update(|r| r.size = Some(image.data.len()));
+    print_checksums(&opt, &image.data);
+
+    match (opt.erase, opt.mass_erase) {
+        (Some(page), _) => status!("    {} page at {:#010x}", "Erasing".green().bold(), page),
+        (None, true) => status!("    {} the whole flash", "Erasing".green().bold()),
+        (None, false) => {}
+    }
+
+    // Start timer.
+    let instant = Instant::now();
+
+    let progress = if report::enabled() {
+        Box::new(|_| {})
+    } else {
+        progress::download(image.data.len())
+    };
+    let options = flash_options(&opt, interface, alt, flash_region(&opt));
+    let address = match cargo_dfu::flash_image(&d, &image, &options, progress) {
+        Ok(address) => address,
+        Err(UtilError::Dfu(dfu_libusb::Error::LibUsb(rusb::Error::Busy))) => interface_busy(),
+        Err(e) => exit_with_error(flash_exit(&e), "flashing binary", e),
+    };
+    report::update(|r| r.address = Some(address));
+    let binary = image.data;
+
+    // Stop timer.
+    let elapsed = instant.elapsed();
+
+    if let Some(mode) = opt.verify {
+        if mode == VerifyMode::AfterReset {
+            status!("    {} after reset", "Verifying".green().bold());
+            // The device may have reset itself already after manifestation.
+            let _ = d.reset();
+        } else {
+            status!("    {} {} bytes", "Verifying".green().bold(), binary.len());
+        }
+
+        // Either way the handle may be stale, so look for the device again.
+        drop(d);
+
+        d = match wait_for_device(&opt, &retries) {
+            Some(d) => d,
+            None => device_not_found(&opt),
+        };
+        let hash = opt.hash.unwrap_or(Hash::Crc32);
+        if let Err(e) = verify_bin(&binary, address, &mut d, interface, alt, hash) {
+            exit_with_error(report::Exit::Verify, "verifying", e);
         }
+    }
+
+    if opt.reset {
+        status!("    {} device", "Resetting".green().bold());
+        match d.reset() {
+            Ok(()) => {}
+            Err(rusb::Error::NoDevice) | Err(rusb::Error::NotFound) => warnings.push(
+                "the device was gone before the reset, it probably manifested by itself"
+                    .to_string(),
+            ),
+            Err(e) => warnings.push(format!("resetting the device failed: {}", e)),
+        }
+    }
+
+    // Give the device time to reset into the application before declaring success.
+    std::thread::sleep(Duration::from_millis(opt.post_flash_delay));
+
+    status!(
+        "    {} in {}s",
+        "Finished".green().bold(),
+        elapsed.as_millis() as f32 / 1000.0
+    );
+    report::update(|r| r.elapsed_secs = Some(elapsed.as_secs_f64()));
+
+    if let Some(pattern) = &opt.selftest {
+        status!(
+            "    {} for {:?}",
+            "Testing".green().bold(),
+            pattern.as_str()
+        );
+        match selftest::run(
+            opt.selftest_port.as_deref(),
+            serial_number.as_deref(),
+            opt.baud_rate,
+            pattern,
+            Duration::from_secs(opt.selftest_timeout),
+        ) {
+            Ok(line) => status!("    {} {}", "Passed".green().bold(), line),
+            Err(e) if report::enabled() => {
+                report::warnings(&warnings);
+                report::fail(
+                    report::Exit::SelfTest,
+                    "selftest",
+                    format!("self-test: {}", e),
+                );
+            }
+            Err(e) => {
+                println!("    {} self-test: {}", "Failed".red().bold(), e);
+                warnings.print();
+                report::exit(report::Exit::SelfTest);
+            }
+        }
+    }
+
+    report::finish(&warnings);
+}
+
+/// Prints the CRC32 of the image to flash, and its SHA-256 with `--hash sha256`.
+fn print_checksums(opt: &Opt, binary: &[u8]) {
+    let crc32 = Hash::Crc32.digest(binary);
+    status!("    {} crc32 {}", "Checksum".green().bold(), crc32);
+    let sha256 = (opt.hash == Some(Hash::Sha256)).then(|| Hash::Sha256.digest(binary));
+    if let Some(sha256) = &sha256 {
+        status!("    {} sha256 {}", "Checksum".green().bold(), sha256);
+    }
+    report::update(|r| {
+        r.crc32 = Some(crc32);
+        r.sha256 = sha256;
+    });
+}
+
+/// Warns about an image that looks built for the wrong target, or refuses it with --strict.
+fn sanity_check(opt: &Opt, image: &Image, warnings: &mut Warnings) {
+    let problems = check_image(image, opt.max_size as usize);
+    if problems.is_empty() {
         return;
     }
+    if opt.strict {
+        report::fail_with_details(
+            report::Exit::Error,
+            "suspicious_image",
+            "the image looks wrong, check --target and the linker script".to_string(),
+            problems
+                .iter()
+                .map(|p| format!("             - {}", p))
+                .collect(),
+        );
+    }
+    for problem in problems {
+        warnings.push(format!("{}, check --target and the linker script", problem));
+    }
+}
+
+/// Flashes the image at `path` to every matching device in turn, going on after failures.
+fn flash_all(opt: &Opt, path: PathBuf, mut warnings: Warnings) -> ! {
+    let image = image_to_bin(
+        path,
+        opt.address_source,
+        opt.hex_fill,
+        opt.fill_byte,
+        &mut warnings,
+    )
+    .unwrap_or_else(|e| exit_with_error(report::Exit::Error, "reading the image", e));
+    sanity_check(opt, &image, &mut warnings);
+    report::update(|r| {
+        r.size = Some(image.data.len());
+        r.address = opt.address.or(image.address);
+    });
+    print_checksums(opt, &image.data);
+
+    let flash = flash_region(opt);
+
+    check_libusb();
+    let devices = device::find_devices(&opt.selector());
+    if devices.is_empty() {
+        device_not_found(opt)
+    }
+
+    let mut flashed = 0;
+    for d in &devices {
+        let label = serial_number(d).unwrap_or_else(|| {
+            format!(
+                "bus {} address {}",
+                d.device().bus_number(),
+                d.device().address()
+            )
+        });
+        status!("    {} {}", "Flashing".green().bold(), label);
+
+        let progress = if report::enabled() {
+            Box::new(|_| {})
+        } else {
+            progress::download(image.data.len())
+        };
+        // A board without the interface fails alone, like one whose download fails.
+        let mut result = select_interface(opt, d).and_then(|(interface, alt)| {
+            let options = flash_options(opt, interface, alt, flash.clone());
+            cargo_dfu::flash_image(d, &image, &options, progress).map(drop)
+        });
+        if result.is_ok() && opt.reset {
+            result = match d.device().open().and_then(|mut h| h.reset()) {
+                Ok(()) | Err(rusb::Error::NoDevice) | Err(rusb::Error::NotFound) => Ok(()),
+                Err(e) => Err(UtilError::Usb(e)),
+            };
+        }
+
+        match &result {
+            Ok(()) => {
+                flashed += 1;
+                status!("    {} {}", "Flashed".green().bold(), label);
+            }
+            Err(e) => status!("    {} {}: {}", "Failed".red().bold(), label, e),
+        }
+        report::update(|r| {
+            r.devices.push(report::DeviceReport {
+                device: label,
+                error: result.err().map(|e| e.to_string()),
+            })
+        });
+    }
+
+    status!(
+        "    {} flashed {} of {} devices",
+        "Finished".green().bold(),
+        flashed,
+        devices.len()
+    );
+    if flashed < devices.len() {
+        report::warnings(&warnings);
+        report::fail(
+            report::Exit::Flash,
+            "flash",
+            format!(
+                "{} of {} devices failed",
+                devices.len() - flashed,
+                devices.len()
+            ),
+        );
+    }
+    report::finish(&warnings)
+}
 
+/// Directory of the cargo project, the one of `--manifest-path` or the current one.
+fn project_dir(opt: &Opt) -> &Path {
+    match opt.manifest_path.as_deref().and_then(Path::parent) {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Resolves the artifact to flash from the cargo project and builds it unless `--no-build` is given.
+fn build(opt: &Opt) -> PathBuf {
     // Try and get the cargo project information.
-    let project = cargo_project::Project::query(".").expect("Couldn't parse the Cargo.toml");
+    if let Some(manifest) = opt.manifest_path.as_ref().filter(|m| !m.is_file()) {
+        report::fail(
+            report::Exit::Build,
+            "manifest",
+            format!("manifest {:?} does not exist", manifest),
+        );
+    }
+    let project_dir = project_dir(opt);
+    let project = match cargo_project::Project::query(project_dir) {
+        Ok(project) => project,
+        Err(e) => report::fail(
+            report::Exit::Build,
+            "project",
+            format!(
+                "reading the cargo project in {:?}: {}, use --firmware to flash a prebuilt image",
+                project_dir, e
+            ),
+        ),
+    };
 
     // Decide what artifact to use.
-    let artifact = if let Some(bin) = &opt.bin {
-        cargo_project::Artifact::Bin(bin)
-    } else if let Some(example) = &opt.example {
-        cargo_project::Artifact::Example(example)
+    let path = if let Some(artifact) = &opt.artifact {
+        resolve_artifact(artifact, project_dir)
     } else {
-        cargo_project::Artifact::Bin(project.name())
-    };
+        let artifact = if let Some(bin) = &opt.bin {
+            cargo_project::Artifact::Bin(bin)
+        } else if let Some(example) = &opt.example {
+            cargo_project::Artifact::Example(example)
+        } else if let Some(package) = &opt.package {
+            // The default binary of a workspace member is named after its package.
+            cargo_project::Artifact::Bin(package)
+        } else {
+            cargo_project::Artifact::Bin(project.name())
+        };
 
-    // Decide what profile to use.
-    let profile = if opt.release {
-        cargo_project::Profile::Release
-    } else {
-        cargo_project::Profile::Dev
-    };
+        // Decide what profile to use.
+        let profile = if opt.release {
+            cargo_project::Profile::Release
+        } else {
+            cargo_project::Profile::Dev
+        };
 
-    // Try and get the artifact path.
-    let path = project
-        .path(
+        // Try and get the artifact path.
+        let host = host_triple().unwrap_or_else(|e| {
+            report::fail(
+                report::Exit::Build,
+                "host",
+                format!("detecting the host triple: {}", e),
+            )
+        });
+        match project.path(
             artifact,
             profile,
             opt.target
                 .as_deref()
                 .map(|target| target.trim_end_matches(".json")),
-            "x86_64-unknown-linux-gnu",
-        )
-        .expect("Couldn't find the build result");
+            &host,
+        ) {
+            Ok(path) => path,
+            Err(e) => report::fail(
+                report::Exit::Build,
+                "project",
+                format!("finding the build result: {}", e),
+            ),
+        }
+    };
+
+    if !opt.no_build {
+        // Remove first two args which is the calling application name and the `dfu` command from cargo.
+        let args = cargo_build_args(std::env::args().skip(2));
 
-    // Remove first two args which is the calling application name and the `dfu` command from cargo.
-    let mut args: Vec<_> = std::env::args().skip(2).collect();
+        // Keep stdout for the report, cargo only prints diagnostics anyway.
+        let stdout = if report::enabled() {
+            Stdio::from(std::io::stderr())
+        } else {
+            Stdio::inherit()
+        };
+        let status = Command::new("cargo")
+            .arg("build")
+            .args(args)
+            .stdout(stdout)
+            .stderr(Stdio::inherit())
+            .status()
+            .unwrap_or_else(|e| {
+                report::fail(
+                    report::Exit::Build,
+                    "build",
+                    format!("running cargo build: {}", e),
+                )
+            });
 
-    // todo, keep as iter. difficult because we want to filter map remove two items at once.
-    // Remove our args as cargo build does not understand them.
-    let flags = ["--pid", "--vid", "--chip"].iter();
-    for flag in flags {
-        if let Some(index) = args.iter().position(|x| x == flag) {
-            args.remove(index);
-            args.remove(index);
+        if !status.success() {
+            report::fail(
+                report::Exit::Build,
+                "build",
+                format!("cargo build failed with {}", status),
+            );
         }
     }
 
-    let status = Command::new("cargo")
-        .arg("build")
-        .args(args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .unwrap()
-        .wait()
-        .unwrap();
+    path
+}
 
-    if !status.success() {
-        exit_with_process_status(status)
+/// Returns the triple of the host, which artifacts are built for when no target is given.
+fn host_triple() -> Result<String, String> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(&rustc)
+        .arg("-vV")
+        .output()
+        .map_err(|e| format!("running {:?}: {}", rustc, e))?;
+    if !output.status.success() {
+        return Err(format!("{:?} -vV failed with {}", rustc, output.status));
     }
 
-    let Some(d) = (if let (Some(v), Some(p)) = (opt.vid, opt.pid) {
-        open_device_with_vid_pid(v, p)
-    } else if let Some(c) = opt.chip {
-        println!("    {} for a connected {}.", "Searching".green().bold(), c);
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(|host| host.trim().to_string())
+        .ok_or_else(|| format!("no host in the output of {:?} -vV", rustc))
+}
 
-        let mut device: Option<rusb::DeviceHandle<GlobalContext>> = None;
+/// Writes the image in the format given by the extension of `output` instead of flashing it.
+fn write_image(output: &Path, binary: &[u8], address: u32) -> std::io::Result<()> {
+    let extension = output.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "bin" => std::fs::write(output, binary),
+        "srec" | "s19" | "s28" | "s37" => {
+            let header = output.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            let srec = srec::encode(binary, address, header).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "the image at {:#010x} extends past the 32 bit address space",
+                        address
+                    ),
+                )
+            })?;
+            std::fs::write(output, srec)
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unsupported output format {:?}", extension),
+        )),
+    }
+}
 
-        let vendor = vendor_map();
+/// How to flash the image on the given interface and alt setting according to the command line.
+fn flash_options(
+    opt: &Opt,
+    interface: u8,
+    alt: u8,
+    flash_region: Option<std::ops::Range<u64>>,
+) -> FlashOptions {
+    FlashOptions {
+        interface,
+        alt,
+        address: opt.address,
+        address_source: opt.address_source,
+        hex_fill: opt.hex_fill,
+        elf_fill: opt.fill_byte,
+        transfer: transfer(opt),
+        flash_region,
+        erase: opt
+            .erase
+            .map(Erase::Page)
+            .or(opt.mass_erase.then_some(Erase::Mass)),
+    }
+}
 
-        if let Some(products) = vendor.get(&c) {
-            for (v, p) in products {
-                if let Some(d) = open_device_with_vid_pid(*v, *p) {
-                    device = Some(d);
-                    break;
-                }
+/// Exit status of a failed `flash_image`, telling a rejected image from a failed download.
+fn flash_exit(e: &UtilError) -> report::Exit {
+    match e {
+        UtilError::MissingAddress
+        | UtilError::Layout(..)
+        | UtilError::OutsideFlash { .. }
+        | UtilError::NotDfuSe => report::Exit::Error,
+        _ => report::Exit::Flash,
+    }
+}
+
+/// Flash of the `--probe-rs-chip`, if any.
+fn flash_region(opt: &Opt) -> Option<std::ops::Range<u64>> {
+    opt.probe_rs_chip.as_deref().map(probe_rs_flash_region)
+}
+
+/// Looks up the flash of `chip` in the probe-rs chip database.
+#[cfg(feature = "probe-rs")]
+fn probe_rs_flash_region(chip: &str) -> std::ops::Range<u64> {
+    cargo_dfu::chip_db::flash_region(chip).unwrap_or_else(|e| {
+        report::fail(
+            report::Exit::Error,
+            "probe_rs",
+            format!("looking up {}: {}", chip, e),
+        )
+    })
+}
+
+#[cfg(not(feature = "probe-rs"))]
+fn probe_rs_flash_region(_: &str) -> std::ops::Range<u64> {
+    report::fail(
+        report::Exit::Error,
+        "probe_rs",
+        "--probe-rs-chip needs cargo-dfu to be built with the probe-rs feature".to_string(),
+    )
+}
+
+/// The timeout and block size of the download given on the command line.
+fn transfer(opt: &Opt) -> dfu::Transfer {
+    dfu::Transfer {
+        timeout: opt.timeout.map_or(dfu::TIMEOUT, Duration::from_millis),
+        size: opt.transfer_size.map(NonZeroU16::get),
+    }
+}
+
+/// Announces the device found and records it in the report.
+fn report_device(d: &rusb::DeviceHandle<GlobalContext>) {
+    status!(
+        "    {} {} at {}",
+        "Found ".green().bold(),
+        product_name(d),
+        device_path(d)
+    );
+    let descriptor = d.device().device_descriptor().ok();
+    report::update(|r| {
+        r.vid = descriptor.as_ref().map(|desc| desc.vendor_id());
+        r.pid = descriptor.as_ref().map(|desc| desc.product_id());
+        r.serial = serial_number(d);
+        r.product = Some(product_name(d));
+    });
+}
+
+/// Prints the DFU functional descriptor and the alt settings of the device.
+fn dump_descriptors(d: &rusb::DeviceHandle<GlobalContext>) {
+    match dfu::functional_descriptor(d) {
+        Some(Ok(descriptor)) => {
+            status!(
+                "    {} DFU {:x}.{:02x}, canDnload {}, canUpload {}, manifestationTolerant {}, willDetach {}",
+                "Descriptor".cyan().bold(),
+                descriptor.dfu_version.0,
+                descriptor.dfu_version.1,
+                descriptor.can_download,
+                descriptor.can_upload,
+                descriptor.manifestation_tolerant,
+                descriptor.will_detach
+            );
+            status!(
+                "               wDetachTimeOut {} ms, wTransferSize {} bytes",
+                descriptor.detach_timeout,
+                descriptor.transfer_size
+            );
+        }
+        Some(Err(e)) => status!(
+            "    {} invalid DFU functional descriptor: {}",
+            "Descriptor".cyan().bold(),
+            e
+        ),
+        None => status!(
+            "    {} no DFU functional descriptor",
+            "Descriptor".cyan().bold()
+        ),
+    }
+
+    match alt_settings(d) {
+        Ok(alts) => {
+            for alt in alts {
+                status!(
+                    "    {} interface {} alt {}: {:?}",
+                    "Alt".cyan().bold(),
+                    alt.interface,
+                    alt.alt,
+                    alt.name
+                );
             }
         }
+        Err(e) => status!(
+            "    {} reading the interface descriptors: {}",
+            "Alt".cyan().bold(),
+            e
+        ),
+    }
+}
 
-        device
-    } else {
-        println!(
-            "    {} for a connected device with known vid/pid pair.",
+/// Resolves the interface and alt setting to use, by name or by number.
+fn select_interface(
+    opt: &Opt,
+    d: &rusb::DeviceHandle<GlobalContext>,
+) -> Result<(u8, u8), UtilError> {
+    match &opt.interface_name {
+        Some(name) => find_alt_setting(d, name),
+        None => Ok((opt.interface.unwrap_or(0), opt.alt.unwrap_or(0))),
+    }
+}
+
+/// Exits unless the interface of the device could be selected.
+fn interface_or_exit(result: Result<(u8, u8), UtilError>) -> (u8, u8) {
+    result.unwrap_or_else(|e| {
+        let exit = match e {
+            UtilError::Usb(_) => report::Exit::NoDevice,
+            _ => report::Exit::Error,
+        };
+        exit_with_error(exit, "selecting the interface", e)
+    })
+}
+
+/// Prints every connected USB device with a DFU interface or a vid/pid pair from the vendor map.
+fn list_devices(opt: &Opt) {
+    check_libusb();
+    let devices = rusb::devices().unwrap_or_else(|e| {
+        exit_with_error(
+            report::Exit::NoDevice,
+            "listing the USB devices",
+            UtilError::Usb(e),
+        )
+    });
+
+    let mut found = vec![];
+    for device in devices.iter() {
+        let Ok(descriptor) = device.device_descriptor() else {
+            continue;
+        };
+        let ids = (descriptor.vendor_id(), descriptor.product_id());
+        let chip = opt
+            .chips
+            .iter()
+            .find(|(_, products)| products.contains(&ids))
+            .map(|(chip, _)| chip.as_str());
+        let mode = if dfu::find_dfu_interface(&device, dfu::PROTOCOL_DFU).is_some() {
+            "DFU mode"
+        } else if dfu::find_dfu_interface(&device, dfu::PROTOCOL_RUNTIME).is_some() {
+            "runtime mode"
+        } else if chip.is_some() {
+            "no DFU interface"
+        } else {
+            continue;
+        };
+
+        // The strings can't be read without access to the device, which is worth showing too.
+        let handle = device.open().ok();
+        let handle = handle.as_ref();
+        let manufacturer = handle.and_then(|h| h.read_manufacturer_string_ascii(&descriptor).ok());
+        let product = handle.and_then(|h| h.read_product_string_ascii(&descriptor).ok());
+        let serial = handle.and_then(|h| h.read_serial_number_string_ascii(&descriptor).ok());
+
+        // Without access to the device there is no OS path, but the port path locates it as well.
+        let path = match handle {
+            Some(h) => device_path(h),
+            None => PortPath {
+                bus: Some(device.bus_number()),
+                ports: device.port_numbers().unwrap_or_default(),
+            }
+            .to_string(),
+        };
+
+        let string = |s: &Option<String>| s.clone().unwrap_or_else(|| "?".to_string());
+        status!(
+            "    {} {:04x}:{:04x} {} {} serial {} at {} on bus {} address {} ({}, {})",
+            "Found".green().bold(),
+            ids.0,
+            ids.1,
+            string(&manufacturer),
+            string(&product),
+            string(&serial),
+            path,
+            device.bus_number(),
+            device.address(),
+            chip.unwrap_or("unknown chip"),
+            mode
+        );
+        found.push(report::FoundReport {
+            vid: ids.0,
+            pid: ids.1,
+            manufacturer,
+            product,
+            serial,
+            path,
+            bus: device.bus_number(),
+            address: device.address(),
+            chip: chip.map(String::from),
+            mode: mode.to_string(),
+        });
+    }
+    report::update(|r| r.found = Some(found));
+}
+
+/// Polls for a device in DFU mode until one shows up or the discovery retries run out.
+///
+/// If meanwhile the only DFU capable device is one running its application, it is detached once
+/// to make it reboot into its bootloader. With `--enter-timeout` both share that time budget
+/// instead of the retry count. With `--wait` it polls until Ctrl-C.
+fn wait_for_device(opt: &Opt, retries: &Retries) -> Option<rusb::DeviceHandle<GlobalContext>> {
+    check_libusb();
+
+    if let Some(path) = &opt.port_path {
+        status!(
+            "    {} for a device on port {}.",
             "Searching".green().bold(),
+            path
         );
+    } else if opt.vid.is_none() || opt.pid.is_none() {
+        if let Some(c) = &opt.chip {
+            status!("    {} for a connected {}.", "Searching".green().bold(), c);
+        } else {
+            status!(
+                "    {} for a connected device with known vid/pid pair.",
+                "Searching".green().bold(),
+            );
+        }
+    }
 
-        let devices: Vec<_> = rusb::devices()
-            .expect("Error with Libusb")
-            .iter()
-            .map(|d| d.device_descriptor().unwrap())
-            .collect();
+    let delays: Box<dyn Iterator<Item = Duration>> = match (opt.search_timeout, opt.enter_timeout) {
+        _ if opt.wait => Box::new(retries.forever()),
+        (Some(timeout), _) => Box::new(retries.backoff_within(Duration::from_secs(timeout))),
+        (None, Some(timeout)) => Box::new(retries.delays_within(Duration::from_secs(timeout))),
+        (None, None) => Box::new(retries.delays(opt.retries)),
+    };
+    let _interrupt = opt.wait.then(interrupt::catch);
 
-        let mut device: Option<rusb::DeviceHandle<GlobalContext>> = None;
+    let mut detached = false;
+    let mut hinted = false;
+    let mut waiting_since = Instant::now();
+    let found = retry::retry(delays, || {
+        if interrupt::interrupted() {
+            return OperationResult::Err(());
+        }
+        if let Some(d) = find_device(opt) {
+            return OperationResult::Ok(d);
+        }
+        if opt.wait && waiting_since.elapsed() >= STILL_WAITING_INTERVAL {
+            status!(
+                "    {} still waiting for a device, press Ctrl-C to stop",
+                "Waiting".green().bold()
+            );
+            waiting_since = Instant::now();
+        }
 
-        for d in devices {
-            for vendor in vendor_map() {
-                if vendor.1.contains(&(d.vendor_id(), d.product_id())) {
-                    if let Some(d) = open_device_with_vid_pid(d.vendor_id(), d.product_id()) {
-                        device = Some(d);
-                        break;
-                    }
+        if !detached {
+            if let Some(device) = find_runtime_device(opt) {
+                status!("    {} device into DFU mode", "Detaching".green().bold());
+                match dfu::detach(&device) {
+                    Ok(()) => {}
+                    Err(e) if opt.detach => status!(
+                        "    {} detaching failed: {}, put the device into DFU mode by hand",
+                        "Warning".yellow().bold(),
+                        e
+                    ),
+                    Err(e) => log::warn!("Detaching failed: {}", e),
                 }
+                detached = true;
+            } else if opt.detach && !hinted {
+                status!(
+                    "    {} no device to detach, put the device into DFU mode by hand",
+                    "Warning".yellow().bold()
+                );
+                hinted = true;
             }
         }
 
-        device
-    }) else {
-        println!(
-            "    {} finding connected devices, have you placed it into bootloader mode?",
-            "Error".red().bold()
+        OperationResult::Retry(())
+    })
+    .ok();
+
+    if interrupt::interrupted() {
+        report::fail(
+            report::Exit::Interrupted,
+            "interrupted",
+            "interrupted while waiting for a device".to_string(),
         );
-        std::process::exit(101);
-    };
+    }
+    found
+}
 
-    println!(
-        "    {} {} {}",
-        "Found ".green().bold(),
-        d.read_manufacturer_string_ascii(&d.device().device_descriptor().unwrap())
-            .unwrap(),
-        d.read_product_string_ascii(&d.device().device_descriptor().unwrap())
-            .unwrap()
+/// Returns the only connected device running an application with a DFU runtime interface, on the
+/// port path, with the serial number and the detach vid/pid given on the command line if any.
+fn find_runtime_device(opt: &Opt) -> Option<rusb::Device<GlobalContext>> {
+    let devices = rusb::devices().ok()?;
+    let mut runtime = devices
+        .iter()
+        .filter(|d| opt.port_path.as_ref().is_none_or(|path| path.matches(d)))
+        .filter(|d| {
+            d.device_descriptor().is_ok_and(|desc| {
+                opt.detach_vid.is_none_or(|vid| vid == desc.vendor_id())
+                    && opt.detach_pid.is_none_or(|pid| pid == desc.product_id())
+            })
+        })
+        .filter(dfu::in_runtime_mode)
+        .filter(|d| {
+            opt.serial.is_none()
+                || d.open().ok().and_then(|h| serial_number(&h)).as_deref() == opt.serial.as_deref()
+        });
+
+    match (runtime.next(), runtime.next()) {
+        (Some(device), None) => Some(device),
+        _ => None,
+    }
+}
+
+/// Searches for a connected device in DFU mode matching the command line. Exits if several
+/// devices match.
+fn find_device(opt: &Opt) -> Option<rusb::DeviceHandle<GlobalContext>> {
+    match device::find_device(&opt.selector()) {
+        Ok(d) => d,
+        Err(UtilError::AmbiguousDevices(devices)) => ambiguous_devices(&devices),
+        Err(e) => exit_with_error(report::Exit::NoDevice, "finding the device", e),
+    }
+}
+
+fn device_not_found(opt: &Opt) -> ! {
+    let candidates = device::candidates(&opt.selector());
+    if let Some(Err(e)) = candidates.iter().find(|d| d.is_err()) {
+        exit_with_error(
+            report::Exit::NoDevice,
+            "opening the device",
+            UtilError::Usb(*e),
+        );
+    }
+
+    if let Some(serial) = &opt.serial {
+        let seen: Vec<_> = candidates
+            .iter()
+            .filter_map(|d| d.as_ref().ok())
+            .filter_map(serial_number)
+            .map(|s| format!("{:?}", s))
+            .collect();
+        report::fail(
+            report::Exit::NoDevice,
+            "device_not_found",
+            format!(
+                "no device with serial number {:?}, serial numbers seen: {}",
+                serial,
+                if seen.is_empty() {
+                    "none".to_string()
+                } else {
+                    seen.join(", ")
+                }
+            ),
+        );
+    }
+    report::fail(
+        report::Exit::NoDevice,
+        "device_not_found",
+        "finding connected devices, have you placed it into bootloader mode?".to_string(),
     );
+}
 
-    println!("    {} {:?}", "Flashing".green().bold(), path);
+/// Exits with an error if libusb can't be initialized, which the global context would panic on.
+fn check_libusb() {
+    if let Err(e) = rusb::Context::new() {
+        exit_with_error(
+            report::Exit::NoDevice,
+            "initializing libusb",
+            UtilError::Usb(e),
+        );
+    }
+}
 
-    let (binary, _) = elf_to_bin(path).unwrap();
+/// Exits with status 1 on an invalid command line instead of clap's own status, in JSON if asked
+/// for. Help and version are printed as usual.
+fn usage_error(e: clap::Error) -> ! {
+    if !e.use_stderr() {
+        e.exit()
+    }
 
-    // Start timer.
-    let instant = Instant::now();
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let json = args.iter().any(|arg| arg == "--format=json")
+        || args
+            .windows(2)
+            .any(|pair| pair[0] == "--format" && pair[1] == "json");
+    if json {
+        report::enable();
+        colored::control::set_override(false);
+    }
 
-    // if let Err(e) = flash_bin(&binary, &d.device()) {
-    //     println!("    {} flashing binary: {:?}", "Error".red().bold(), e);
-    // }
+    let text = e.to_string();
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let message = lines.next().unwrap_or_default();
+    report::fail_with_details(
+        report::Exit::Error,
+        "usage",
+        message.trim_start_matches("error: ").to_string(),
+        lines.map(|line| format!("    {}", line.trim())).collect(),
+    )
+}
 
-    match flash_bin(&binary, &d.device()) {
-        Err(utils::UtilError::Dfu(dfu_libusb::Error::LibUsb(rusb::Error::NoDevice))) => {
-            // works for me?
-        }
-        Err(e) => println!("    {} flashing binary: {:?}", "Error".red().bold(), e),
-        _ => (),
+fn exit_with_error(exit: report::Exit, context: &str, e: UtilError) -> ! {
+    report::fail(exit, e.code(), format!("{}: {}", context, e))
+}
+
+fn ambiguous_devices(devices: &[rusb::Device<GlobalContext>]) -> ! {
+    let details = devices
+        .iter()
+        .map(|device| {
+            let path = PortPath {
+                bus: Some(device.bus_number()),
+                ports: device.port_numbers().unwrap_or_default(),
+            };
+            format!(
+                "           bus {} address {} port path {} serial {}",
+                device.bus_number(),
+                device.address(),
+                path,
+                device
+                    .open()
+                    .ok()
+                    .and_then(|d| serial_number(&d))
+                    .unwrap_or_else(|| "?".to_string())
+            )
+        })
+        .collect();
+    report::fail_with_details(
+        report::Exit::NoDevice,
+        "ambiguous_devices",
+        format!(
+            "{} devices match, pick one with --serial, --port-path or --bus and --usb-address:",
+            devices.len()
+        ),
+        details,
+    )
+}
+
+fn interface_busy() -> ! {
+    let mut details = vec![];
+    if cfg!(target_os = "linux") {
+        details.push(format!(
+            "    {} ModemManager grabs serial capable bootloaders, stop it with `sudo systemctl stop ModemManager`",
+            "Hint".yellow().bold()
+        ));
+    }
+    report::fail_with_details(
+        report::Exit::Flash,
+        "interface_busy",
+        "the DFU interface is in use by another process — close dfu-util/ModemManager and retry"
+            .to_string(),
+        details,
+    )
+}
+
+/// Resolves an explicit `--artifact` path, first relative to the current directory and then
+/// relative to the workspace root.
+fn resolve_artifact(artifact: &Path, project_dir: &Path) -> PathBuf {
+    if artifact.is_absolute() || artifact.exists() {
+        return artifact.to_path_buf();
     }
 
-    // Stop timer.
-    let elapsed = instant.elapsed();
-    println!(
-        "    {} in {}s",
-        "Finished".green().bold(),
-        elapsed.as_millis() as f32 / 1000.0
-    );
+    // Cargo prints artifact paths relative to the workspace root.
+    workspace_root(project_dir)
+        .into_iter()
+        .chain(std::iter::once(project_dir.to_path_buf()))
+        .map(|root| root.join(artifact))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| artifact.to_path_buf())
 }
 
-#[cfg(unix)]
-fn exit_with_process_status(status: std::process::ExitStatus) -> ! {
-    use std::os::unix::process::ExitStatusExt;
-    let status = status.code().or_else(|| status.signal()).unwrap_or(1);
-    std::process::exit(status)
+/// Asks `cargo metadata` for the root of the workspace containing `project_dir`.
+fn workspace_root(project_dir: &Path) -> Option<PathBuf> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(project_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    metadata["workspace_root"].as_str().map(PathBuf::from)
 }
 
-#[cfg(not(unix))]
-fn exit_with_process_status(status: std::process::ExitStatus) -> ! {
-    let status = status.code().unwrap_or(1);
-    std::process::exit(status)
+fn parse_hex_32(input: &str) -> Result<u32, std::num::ParseIntError> {
+    input.strip_prefix("0x").map_or_else(
+        || input.parse(),
+        |stripped| u32::from_str_radix(stripped, 16),
+    )
+}
+
+fn parse_hex_8(input: &str) -> Result<u8, std::num::ParseIntError> {
+    input.strip_prefix("0x").map_or_else(
+        || input.parse(),
+        |stripped| u8::from_str_radix(stripped, 16),
+    )
 }
 
 fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
@@ -193,9 +1301,96 @@ fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
     )
 }
 
+/// When to color the output.
+#[derive(Debug, Clone, Copy)]
+enum ColorChoice {
+    /// Unless `NO_COLOR` is set or stdout is not a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("unknown color choice {:?}", s)),
+        }
+    }
+}
+
+/// Part of the run to do alone, everything by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// Only build and report the artifact.
+    Build,
+    /// Only find the device and flash the artifact built before, or the firmware.
+    Flash,
+}
+
+impl std::str::FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "build" => Ok(Phase::Build),
+            "flash" => Ok(Phase::Flash),
+            _ => Err(format!("unknown phase {:?}", s)),
+        }
+    }
+}
+
+/// How to report the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// Colored status lines.
+    Human,
+    /// A single JSON object on stdout.
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {:?}", s)),
+        }
+    }
+}
+
+impl Opt {
+    /// The device to flash according to the command line.
+    fn selector(&self) -> DeviceSelector {
+        DeviceSelector {
+            vid_pid: self.vid.zip(self.pid),
+            chip: self.chip.clone(),
+            serial: self.serial.clone(),
+            port_path: self.port_path.clone(),
+            bus: self.bus,
+            address: self.usb_address,
+            chips: self.chips.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Opt {
+    /// Only build (`build`) or only flash the artifact built before (`flash`), both by default
+    #[clap(
+        name = "phase",
+        possible_values = args::PHASES,
+        conflicts_with_all = &["upload", "list-chips", "list-devices", "list-alts"]
+    )]
+    phase: Option<Phase>,
+
     // `cargo build` arguments
     #[clap(name = "binary", long = "bin")]
     bin: Option<String>,
@@ -216,13 +1411,281 @@ struct Opt {
     #[clap(long)]
     features: Vec<String>,
 
+    /// Path to the ELF to flash, bypassing the bin/example/package resolution
+    #[clap(name = "artifact", long = "artifact", parse(from_os_str))]
+    artifact: Option<PathBuf>,
+    /// Prebuilt ELF, Intel HEX or raw .bin image to flash, without looking for a cargo project
+    #[clap(
+        name = "firmware",
+        long = "firmware",
+        parse(from_os_str),
+        conflicts_with = "artifact"
+    )]
+    firmware: Option<PathBuf>,
+    /// Flash the existing artifact without running `cargo build` first
+    #[clap(name = "no-build", long = "no-build")]
+    no_build: bool,
+
     #[clap(name = "pid", long = "pid", parse(try_from_str = parse_hex_16))]
     pid: Option<u16>,
     #[clap(name = "vid", long = "vid",  parse(try_from_str = parse_hex_16))]
     vid: Option<u16>,
 
-    #[clap(name = "chip", long = "chip")]
+    /// Detach the device running its application into DFU mode, warning if that is not possible
+    #[clap(name = "detach", long = "detach")]
+    detach: bool,
+    /// Vendor id of the device to detach, while it runs its application
+    #[clap(name = "detach-vid", long = "detach-vid", parse(try_from_str = parse_hex_16))]
+    detach_vid: Option<u16>,
+    /// Product id of the device to detach, while it runs its application
+    #[clap(name = "detach-pid", long = "detach-pid", parse(try_from_str = parse_hex_16))]
+    detach_pid: Option<u16>,
+
+    /// Number of times to retry the device search, defaults to --max-retries-per-phase
+    #[clap(name = "retries", long = "retries")]
+    retries: Option<usize>,
+    /// Delay between retries in milliseconds
+    #[clap(name = "delay", long = "delay", default_value = "500")]
+    delay: u64,
+    /// Seconds to wait for a device in DFU mode, detaching a runtime mode device if needed
+    #[clap(name = "enter-timeout", long = "enter-timeout")]
+    enter_timeout: Option<u64>,
+    /// Wait for a matching device until one shows up or Ctrl-C is pressed
+    #[clap(
+        name = "wait",
+        long = "wait",
+        conflicts_with_all = &["retries", "search-timeout", "enter-timeout"]
+    )]
+    wait: bool,
+    /// Seconds to search for the device, polling quickly at first and backing off, instead of
+    /// --retries times every --delay
+    #[clap(
+        name = "search-timeout",
+        long = "search-timeout",
+        conflicts_with_all = &["retries", "delay", "enter-timeout"]
+    )]
+    search_timeout: Option<u64>,
+    /// Retries of the device search, also after detaching and before verifying, unless --retries
+    /// is given [default: 60]
+    #[clap(name = "max-retries-per-phase", long = "max-retries-per-phase")]
+    max_retries_per_phase: Option<usize>,
+    /// Give up retrying once this many seconds have passed since start
+    #[clap(name = "deadline", long = "deadline")]
+    deadline: Option<u64>,
+
+    /// Reset the device after flashing so it starts the new firmware
+    #[clap(name = "reset", long = "reset")]
+    reset: bool,
+    /// Milliseconds to wait after flashing before reporting success
+    #[clap(
+        name = "post-flash-delay",
+        long = "post-flash-delay",
+        default_value = "250"
+    )]
+    post_flash_delay: u64,
+
+    /// Byte to fill the gaps between the records of an Intel HEX file with
+    #[clap(
+        name = "hex-fill",
+        long = "hex-fill",
+        default_value = "0xff",
+        parse(try_from_str = parse_hex_8)
+    )]
+    hex_fill: u8,
+    /// Byte to fill the gaps between the segments of an ELF file with
+    #[clap(
+        name = "fill-byte",
+        long = "fill-byte",
+        default_value = "0x00",
+        parse(try_from_str = parse_hex_8)
+    )]
+    fill_byte: u8,
+
+    /// Address to write the image at instead of the one it is linked at, e.g. 0x08008000
+    #[clap(name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+    address: Option<u32>,
+
+    /// Write the image to this .bin or .srec file instead of flashing it
+    #[clap(name = "output", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Timeout of every DFU control transfer in milliseconds [default: 3000]
+    #[clap(name = "timeout", long = "timeout")]
+    timeout: Option<u64>,
+    /// Bytes per download block, instead of the transfer size reported by the device
+    #[clap(name = "transfer-size", long = "transfer-size")]
+    transfer_size: Option<NonZeroU16>,
+
+    /// Read the memory of the device into this file instead of flashing
+    #[clap(name = "upload", long = "upload", parse(from_os_str))]
+    upload: Option<PathBuf>,
+    /// Number of bytes to read with --upload, everything the device sends by default
+    #[clap(
+        name = "length",
+        long = "length",
+        requires = "upload",
+        parse(try_from_str = parse_hex_32)
+    )]
+    length: Option<u32>,
+
+    /// Print the DFU descriptors and alt settings of the device, also passed on to cargo
+    #[clap(short = 'v', long = "verbose")]
+    verbose: bool,
+
+    /// Flash every matching device in turn instead of requiring a single one
+    #[clap(
+        name = "all",
+        long = "all",
+        conflicts_with_all = &["verify", "selftest", "upload"]
+    )]
+    all: bool,
+
+    /// Also print the SHA-256 of the image, and compare it instead of the CRC32 with --verify
+    #[clap(
+        name = "hash",
+        long = "hash",
+        possible_values = &["crc32", "sha256"]
+    )]
+    hash: Option<Hash>,
+
+    /// Size in bytes above which the image is considered suspicious
+    #[clap(
+        name = "max-size",
+        long = "max-size",
+        default_value = "0x200000",
+        parse(try_from_str = parse_hex_32)
+    )]
+    max_size: u32,
+    /// Refuse to flash images that look wrong instead of warning about them
+    #[clap(name = "strict", long = "strict")]
+    strict: bool,
+
+    /// Search for the device while cargo builds instead of after the build
+    #[clap(
+        name = "search-before-build",
+        long = "search-before-build",
+        conflicts_with_all = &["upload", "dry-run", "output", "all"]
+    )]
+    search_before_build: bool,
+
+    /// Mass erase a DfuSe device before flashing
+    #[clap(
+        name = "mass-erase",
+        long = "mass-erase",
+        conflicts_with_all = &["erase", "upload", "dry-run", "output"]
+    )]
+    mass_erase: bool,
+    /// Erase the page of a DfuSe device at this address before flashing
+    #[clap(
+        name = "erase",
+        long = "erase",
+        conflicts_with_all = &["upload", "dry-run", "output"],
+        parse(try_from_str = parse_hex_32)
+    )]
+    erase: Option<u32>,
+
+    /// Build and convert the image and show where it would go, without looking for a device
+    #[clap(name = "dry-run", long = "dry-run", conflicts_with = "output")]
+    dry_run: bool,
+
+    /// Which ELF program header addresses to flash the segments at
+    #[clap(
+        name = "address-source",
+        long = "address-source",
+        default_value = "auto",
+        possible_values = &["physical", "virtual", "auto"]
+    )]
+    address_source: AddressSource,
+
+    /// Read the flash back and compare it with the image, right away or after resetting the device
+    #[clap(
+        name = "verify",
+        long = "verify",
+        require_equals = true,
+        min_values = 0,
+        default_missing_value = "immediate",
+        possible_values = &["immediate", "after-reset"]
+    )]
+    verify: Option<VerifyMode>,
+
+    /// Flash the DFU interface whose name contains this string
+    #[clap(
+        name = "interface-name",
+        long = "interface-name",
+        conflicts_with_all = &["interface", "alt"]
+    )]
+    interface_name: Option<String>,
+    /// Number of the DFU interface to flash [default: 0]
+    #[clap(name = "interface", long = "interface")]
+    interface: Option<u8>,
+    /// Alt setting of the DFU interface to flash, e.g. 1 for the option bytes of STM32 [default: 0]
+    #[clap(name = "alt", long = "alt")]
+    alt: Option<u8>,
+
+    /// After flashing, wait for a line matching this regex on the board's serial port
+    #[clap(name = "selftest", long = "selftest", parse(try_from_str = regex::Regex::new))]
+    selftest: Option<regex::Regex>,
+    /// Serial port to read the self-test output from, detected automatically by default
+    #[clap(name = "selftest-port", long = "selftest-port")]
+    selftest_port: Option<String>,
+    /// Seconds to wait for the self-test line
+    #[clap(
+        name = "selftest-timeout",
+        long = "selftest-timeout",
+        default_value = "10"
+    )]
+    selftest_timeout: u64,
+    /// Baud rate of the self-test serial port
+    #[clap(name = "baud-rate", long = "baud-rate", default_value = "115200")]
+    baud_rate: u32,
+
+    /// Check the image against the flash of this chip from the probe-rs chip database
+    #[clap(name = "probe-rs-chip", long = "probe-rs-chip")]
+    probe_rs_chip: Option<String>,
+
+    /// Chip whose vid/pid pairs select the device, see --list-chips
+    #[clap(name = "chip", long = "chip", env = "CARGO_DFU_CHIP")]
     chip: Option<String>,
+
+    /// Only use the device with this USB serial number
+    #[clap(name = "serial", long = "serial")]
+    serial: Option<String>,
+
+    /// Only use the device plugged in at this chain of hub ports, e.g. 1.4.2 or 3-1.4.2
+    #[clap(name = "port-path", long = "port-path")]
+    port_path: Option<PortPath>,
+    /// Only use the device on this USB bus
+    #[clap(name = "bus", long = "bus")]
+    bus: Option<u8>,
+    /// Only use the device with this address on its USB bus, as shown by --list-devices
+    #[clap(name = "usb-address", long = "usb-address")]
+    usb_address: Option<u8>,
     #[clap(name = "list-chips", long = "list-chips")]
     list_chips: bool,
+    /// List the DFU alt settings and memory layouts of the connected device
+    #[clap(name = "list-alts", long = "list-alts")]
+    list_alts: bool,
+    /// List the connected DFU capable devices and whether they are in DFU or runtime mode
+    #[clap(name = "list-devices", long = "list-devices")]
+    list_devices: bool,
+
+    /// Color the output, `auto` leaves it plain if NO_COLOR is set or stdout is not a terminal
+    #[clap(
+        name = "color",
+        long = "color",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"]
+    )]
+    color: ColorChoice,
+    /// Print a single JSON object describing the run instead of status lines
+    #[clap(
+        name = "format",
+        long = "format",
+        default_value = "human",
+        possible_values = &["human", "json"]
+    )]
+    format: Format,
+
+    /// The built-in chips merged with those of the config files
+    #[clap(skip)]
+    chips: config::ChipMap,
 }
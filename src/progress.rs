@@ -0,0 +1,49 @@
+//! Download progress, as a bar on terminals and as periodic lines in logs.
+
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+
+/// Percentage of the image between two progress lines when stdout is not a terminal.
+const LOG_STEP: usize = 10;
+
+/// Returns a callback to report the progress of downloading `len` bytes, given the number of bytes
+/// written by every call.
+pub fn download(len: usize) -> Box<dyn FnMut(usize)> {
+    if std::io::stdout().is_terminal() {
+        let bar = ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stdout());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "    {prefix} [{bar:40}] {bytes}/{total_bytes} ({binary_bytes_per_sec})",
+            )
+            .expect("valid progress template")
+            .progress_chars("=> "),
+        );
+        bar.set_prefix("Download".green().bold().to_string());
+
+        Box::new(move |n| {
+            bar.inc(n as u64);
+            if bar.position() >= len as u64 {
+                bar.finish();
+            }
+        })
+    } else {
+        let mut written = 0;
+        let mut reported = 0;
+
+        Box::new(move |n| {
+            written += n;
+            let percent = written * 100 / len.max(1);
+            if percent >= reported + LOG_STEP || (written >= len && reported < 100) {
+                println!(
+                    "    {} {}% ({}/{} bytes)",
+                    "Download".green().bold(),
+                    percent,
+                    written,
+                    len
+                );
+                reported = percent;
+            }
+        })
+    }
+}
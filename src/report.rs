@@ -0,0 +1,216 @@
+//! Machine readable summary of a run, printed instead of the human output with `--format json`.
+
+use cargo_dfu::warnings::Warnings;
+use colored::Colorize;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Prints a line of the human output, unless the run is reported as JSON.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !crate::report::enabled() {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Exit status of a run, telling scripts what went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Success = 0,
+    /// Any other error, e.g. an invalid image or option.
+    Error = 1,
+    /// The project could not be built, or the artifact not found.
+    Build = 2,
+    /// No device or several matched.
+    NoDevice = 3,
+    /// The download or upload failed.
+    Flash = 4,
+    /// The flash content differs from the image.
+    Verify = 5,
+    /// The firmware did not pass the self-test.
+    SelfTest = 6,
+    /// Ctrl-C was pressed while waiting for a device, as if killed by SIGINT.
+    Interrupted = 130,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REPORT: Mutex<Report> = Mutex::new(Report::new());
+
+/// What was flashed where, and how it went.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub artifact: Option<PathBuf>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial: Option<String>,
+    pub product: Option<String>,
+    pub size: Option<usize>,
+    pub address: Option<u32>,
+    /// Checksums of the image, the SHA-256 only with `--hash sha256`.
+    pub crc32: Option<String>,
+    pub sha256: Option<String>,
+    pub elapsed_secs: Option<f64>,
+    pub success: bool,
+    pub error: Option<ErrorReport>,
+    pub warnings: Vec<String>,
+    /// Outcome for every device flashed with `--all`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceReport>,
+    /// Known chips, with `--list-chips`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chips: Option<Vec<ChipReport>>,
+    /// Devices plugged in, with `--list-devices`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub found: Option<Vec<FoundReport>>,
+    /// Alt settings of the device, with `--list-alts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alts: Option<Vec<AltReport>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceReport {
+    /// Serial number of the device, or its bus and address if it has none.
+    pub device: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChipReport {
+    pub name: String,
+    pub ids: Vec<UsbIds>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsbIds {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FoundReport {
+    pub vid: u16,
+    pub pid: u16,
+    /// The strings are missing without access to the device.
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub path: String,
+    pub bus: u8,
+    pub address: u8,
+    pub chip: Option<String>,
+    /// `DFU mode`, `runtime mode` or `no DFU interface`.
+    pub mode: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AltReport {
+    pub interface: u8,
+    pub alt: u8,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// Stable name of the kind of error, e.g. `usb` or `device_not_found`.
+    pub code: String,
+    pub message: String,
+    pub details: Vec<String>,
+}
+
+impl Report {
+    const fn new() -> Self {
+        Report {
+            artifact: None,
+            vid: None,
+            pid: None,
+            serial: None,
+            product: None,
+            size: None,
+            address: None,
+            crc32: None,
+            sha256: None,
+            elapsed_secs: None,
+            success: false,
+            error: None,
+            warnings: vec![],
+            devices: vec![],
+            chips: None,
+            found: None,
+            alts: None,
+        }
+    }
+}
+
+/// Reports the run as JSON from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records facts about the run.
+pub fn update(f: impl FnOnce(&mut Report)) {
+    f(&mut REPORT.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+/// Prints the warnings, as part of the report if enabled.
+pub fn warnings(warnings: &Warnings) {
+    if enabled() {
+        update(|r| r.warnings = warnings.iter().map(String::from).collect());
+    } else {
+        warnings.print();
+    }
+}
+
+/// Ends a successful run.
+pub fn finish(warnings: &Warnings) -> ! {
+    self::warnings(warnings);
+    update(|r| r.success = true);
+    print();
+    exit(Exit::Success)
+}
+
+/// Exits with an error.
+pub fn fail(exit: Exit, code: &str, message: String) -> ! {
+    fail_with_details(exit, code, message, vec![])
+}
+
+/// Exits with an error, followed by lines of details in the human output.
+pub fn fail_with_details(exit: Exit, code: &str, message: String, details: Vec<String>) -> ! {
+    if enabled() {
+        update(|r| {
+            r.error = Some(ErrorReport {
+                code: code.to_string(),
+                message,
+                details: details.iter().map(|d| d.trim().to_string()).collect(),
+            })
+        });
+        print();
+    } else {
+        println!("    {} {}", "Error".red().bold(), message);
+        for line in details {
+            println!("{}", line);
+        }
+    }
+    self::exit(exit)
+}
+
+/// Ends the run with `exit` as status.
+pub fn exit(exit: Exit) -> ! {
+    std::process::exit(exit as i32)
+}
+
+fn print() {
+    if enabled() {
+        let report = REPORT.lock().unwrap_or_else(|e| e.into_inner());
+        println!(
+            "{}",
+            serde_json::to_string(&*report).expect("the report serializes")
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Retry settings of the device search, the only step that waits on the device.
+//!
+//! The search runs at the start, after detaching a device running its application and again before
+//! verifying. Opening and flashing the device found are not retried.
+
+use retry::delay::{Exponential, Fixed};
+use std::time::{Duration, Instant};
+
+/// Number of retries of the device search when neither `--retries` nor `--max-retries-per-phase`
+/// is set.
+pub const DEFAULT_RETRIES: usize = 60;
+
+/// First delay of a phase retried with backoff, doubled on every retry up to `MAX_BACKOFF`.
+const FIRST_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+pub struct Retries {
+    /// Cap of the device search unless `--retries` is given.
+    per_phase: Option<usize>,
+    delay: Duration,
+    /// Point in time after which no phase retries anymore.
+    deadline: Option<Instant>,
+}
+
+impl Retries {
+    pub fn new(per_phase: Option<usize>, delay: Duration, deadline: Option<Duration>) -> Self {
+        Retries {
+            per_phase,
+            delay,
+            deadline: deadline.map(|deadline| Instant::now() + deadline),
+        }
+    }
+
+    /// Number of retries of a phase, `explicit` being the phase specific flag.
+    pub fn count(&self, explicit: Option<usize>) -> usize {
+        explicit.or(self.per_phase).unwrap_or(DEFAULT_RETRIES)
+    }
+
+    /// Delays to pass to `retry::retry` for a phase, stopping early once the deadline passed.
+    pub fn delays(&self, explicit: Option<usize>) -> impl Iterator<Item = Duration> + '_ {
+        Fixed::from(self.delay)
+            .take(self.count(explicit))
+            .take_while(move |delay| !self.expires_within(*delay))
+    }
+
+    /// Delays for a phase bounded by time instead of a retry count.
+    pub fn delays_within(&self, budget: Duration) -> impl Iterator<Item = Duration> + '_ {
+        let end = Instant::now() + budget;
+        Fixed::from(self.delay)
+            .take_while(move |delay| Instant::now() + *delay <= end && !self.expires_within(*delay))
+    }
+
+    /// Delays for a phase retried until it succeeds, or the deadline passes.
+    pub fn forever(&self) -> impl Iterator<Item = Duration> + '_ {
+        Fixed::from(self.delay).take_while(move |delay| !self.expires_within(*delay))
+    }
+
+    /// Delays for a phase bounded by time, starting short and doubling up to a cap. The last
+    /// delay is shortened so the phase is retried once more right at the end of the budget.
+    pub fn backoff_within(&self, budget: Duration) -> impl Iterator<Item = Duration> + '_ {
+        let end = Instant::now() + budget;
+        Exponential::from(FIRST_BACKOFF)
+            .map_while(move |delay| {
+                let left = end.checked_duration_since(Instant::now())?;
+                (!left.is_zero()).then(|| delay.min(MAX_BACKOFF).min(left))
+            })
+            .take_while(move |delay| !self.expires_within(*delay))
+    }
+
+    /// Returns true when the deadline passed or passes within `duration`.
+    pub fn expires_within(&self, duration: Duration) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() + duration > deadline)
+    }
+}
@@ -0,0 +1,210 @@
+//! Post-flash self-test: waits for the firmware to print a known line over its serial port.
+
+use regex::Regex;
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum SelftestError {
+    /// No single serial port could be picked, with the names of the candidates seen.
+    NoPort(Vec<String>),
+    Serial(serialport::Error),
+    Io(std::io::Error),
+    /// No line matched before the timeout, with the last line received.
+    Timeout(Option<String>),
+}
+
+impl fmt::Display for SelftestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelftestError::NoPort(ports) if ports.is_empty() => {
+                write!(
+                    f,
+                    "no USB serial port showed up, pass it with --selftest-port"
+                )
+            }
+            SelftestError::NoPort(ports) => write!(
+                f,
+                "can't tell which serial port to use ({}), pass it with --selftest-port",
+                ports.join(", ")
+            ),
+            SelftestError::Serial(e) => write!(f, "serial port error: {}", e),
+            SelftestError::Io(e) => write!(f, "could not read the serial port: {}", e),
+            SelftestError::Timeout(None) => write!(f, "timed out without receiving a line"),
+            SelftestError::Timeout(Some(line)) => {
+                write!(f, "timed out, last line received was {:?}", line)
+            }
+        }
+    }
+}
+
+/// Opens `port`, or the serial port of the freshly flashed board, and waits for a line matching
+/// `pattern`. Returns the matching line.
+///
+/// Without an explicit port the USB serial port with the same serial number as the flashed device
+/// is used, or the only USB serial port if there is exactly one.
+pub fn run(
+    port: Option<&str>,
+    serial_number: Option<&str>,
+    baud_rate: u32,
+    pattern: &Regex,
+    timeout: Duration,
+) -> Result<String, SelftestError> {
+    let deadline = Instant::now() + timeout;
+
+    // The application has to enumerate first, so keep looking for its port until the deadline.
+    let name = match port {
+        Some(port) => port.to_string(),
+        None => loop {
+            match find_port(&SystemPorts, serial_number) {
+                Ok(name) => break name,
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        },
+    };
+
+    let mut port = serialport::new(&name, baud_rate)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .map_err(SelftestError::Serial)?;
+
+    let mut pending = vec![];
+    let mut last_line = None;
+    let mut buffer = [0u8; 256];
+    while Instant::now() < deadline {
+        let n = match port.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(SelftestError::Io(e)),
+        };
+        pending.extend_from_slice(&buffer[..n]);
+
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            if pattern.is_match(&line) {
+                return Ok(line);
+            }
+            last_line = Some(line);
+        }
+    }
+
+    Err(SelftestError::Timeout(last_line))
+}
+
+/// Lists the serial ports to pick the self-test port from.
+pub trait PortList {
+    fn available_ports(&self) -> serialport::Result<Vec<SerialPortInfo>>;
+}
+
+/// The serial ports of this machine.
+pub struct SystemPorts;
+
+impl PortList for SystemPorts {
+    fn available_ports(&self) -> serialport::Result<Vec<SerialPortInfo>> {
+        serialport::available_ports()
+    }
+}
+
+fn find_port<L: PortList>(list: &L, serial_number: Option<&str>) -> Result<String, SelftestError> {
+    let ports: Vec<(String, UsbPortInfo)> = list
+        .available_ports()
+        .map_err(SelftestError::Serial)?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(info) => Some((port.port_name, info)),
+            _ => None,
+        })
+        .collect();
+
+    if let Some((name, _)) = ports
+        .iter()
+        .find(|(_, info)| serial_number.is_some() && info.serial_number.as_deref() == serial_number)
+    {
+        return Ok(name.clone());
+    }
+
+    match ports.as_slice() {
+        [(name, _)] => Ok(name.clone()),
+        _ => Err(SelftestError::NoPort(
+            ports.into_iter().map(|(name, _)| name).collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with a fixed list of ports.
+    struct FakePorts(Vec<SerialPortInfo>);
+
+    impl PortList for FakePorts {
+        fn available_ports(&self) -> serialport::Result<Vec<SerialPortInfo>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn usb(name: &str, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x1209,
+                pid: 0x0001,
+                serial_number: serial_number.map(str::to_string),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn no_port(result: Result<String, SelftestError>) -> Vec<String> {
+        match result {
+            Err(SelftestError::NoPort(ports)) => ports,
+            other => panic!("expected NoPort, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn matches_the_serial_number() {
+        let ports = FakePorts(vec![
+            usb("/dev/ttyACM0", Some("A")),
+            usb("/dev/ttyACM1", Some("B")),
+        ]);
+        assert_eq!(find_port(&ports, Some("B")).unwrap(), "/dev/ttyACM1");
+    }
+
+    #[test]
+    fn falls_back_to_the_only_usb_port() {
+        let ports = FakePorts(vec![
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+            usb("/dev/ttyACM0", Some("A")),
+        ]);
+        assert_eq!(find_port(&ports, Some("B")).unwrap(), "/dev/ttyACM0");
+        assert_eq!(find_port(&ports, None).unwrap(), "/dev/ttyACM0");
+    }
+
+    #[test]
+    fn reports_no_usb_port() {
+        assert!(no_port(find_port(&FakePorts(vec![]), Some("A"))).is_empty());
+    }
+
+    #[test]
+    fn reports_ambiguous_ports() {
+        let ports = FakePorts(vec![
+            usb("/dev/ttyACM0", None),
+            usb("/dev/ttyACM1", Some("A")),
+        ]);
+        assert_eq!(
+            no_port(find_port(&ports, Some("B"))),
+            vec!["/dev/ttyACM0", "/dev/ttyACM1"]
+        );
+        assert_eq!(no_port(find_port(&ports, None)).len(), 2);
+    }
+}
@@ -0,0 +1,160 @@
+//! Motorola S-record encoder.
+
+use std::fmt::Write;
+
+/// Number of data bytes per record, the common choice of srec_cat and objcopy.
+const BYTES_PER_RECORD: usize = 32;
+
+/// Encodes `data` placed at `address` as S-records, using the narrowest address width that fits.
+///
+/// The output starts with an S0 header carrying `header`, followed by the data records, an S5/S6
+/// record count when it fits and the matching S7/S8/S9 termination record pointing at `address`.
+/// Returns `None` when `data` extends past the 32 bit address space.
+pub fn encode(data: &[u8], address: u32, header: &str) -> Option<String> {
+    let end = u64::from(address) + data.len().saturating_sub(1) as u64;
+    let (data_type, end_type, address_len) = if end <= 0xffff {
+        (1, 9, 2)
+    } else if end <= 0xff_ffff {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+
+    let mut out = String::new();
+    record(&mut out, 0, 0, 2, header.as_bytes());
+
+    let mut count = 0;
+    for (i, chunk) in data.chunks(BYTES_PER_RECORD).enumerate() {
+        let chunk_address = u32::try_from(i * BYTES_PER_RECORD)
+            .ok()
+            .and_then(|offset| address.checked_add(offset))?;
+        record(&mut out, data_type, chunk_address, address_len, chunk);
+        count += 1;
+    }
+
+    if count <= 0xffff {
+        record(&mut out, 5, count, 2, &[]);
+    } else if count <= 0xff_ffff {
+        record(&mut out, 6, count, 3, &[]);
+    }
+    record(&mut out, end_type, address, address_len, &[]);
+
+    Some(out)
+}
+
+/// Appends a single `S<kind>` record with an `address_len` bytes wide address.
+fn record(out: &mut String, kind: u8, address: u32, address_len: usize, data: &[u8]) {
+    let address = &address.to_be_bytes()[4 - address_len..];
+    let count = (address.len() + data.len() + 1) as u8;
+    let sum = address
+        .iter()
+        .chain(data)
+        .fold(count, |sum, b| sum.wrapping_add(*b));
+
+    write!(out, "S{}{:02X}", kind, count).unwrap();
+    for b in address.iter().chain(data) {
+        write!(out, "{:02X}", b).unwrap();
+    }
+    writeln!(out, "{:02X}", !sum).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the records back into `(kind, address, data)`, checking every checksum.
+    fn decode(srec: &str) -> Vec<(u8, u32, Vec<u8>)> {
+        srec.lines()
+            .map(|line| {
+                let kind = line.as_bytes()[1] - b'0';
+                let bytes: Vec<u8> = (2..line.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                    .collect();
+                assert_eq!(bytes[0] as usize, bytes.len() - 1, "{}", line);
+                let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+                assert_eq!(sum, 0xff, "bad checksum in {}", line);
+
+                let address_len = match kind {
+                    0 | 1 | 5 | 9 => 2,
+                    2 | 6 | 8 => 3,
+                    _ => 4,
+                };
+                let address = bytes[1..=address_len]
+                    .iter()
+                    .fold(0, |address, b| address << 8 | u32::from(*b));
+                (
+                    kind,
+                    address,
+                    bytes[address_len + 1..bytes.len() - 1].to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    fn round_trip(data: &[u8], address: u32) -> (Vec<u8>, Vec<u8>) {
+        let records = decode(&encode(data, address, "firmware").unwrap());
+        let kinds = records.iter().map(|(kind, _, _)| *kind).collect();
+
+        let mut decoded = vec![];
+        for (kind, record_address, bytes) in &records {
+            if (1..=3).contains(kind) {
+                assert_eq!(*record_address, address + decoded.len() as u32);
+                decoded.extend_from_slice(bytes);
+            }
+        }
+        assert_eq!(records[0].2, b"firmware");
+        assert_eq!(records.last().unwrap().1, address);
+
+        (decoded, kinds)
+    }
+
+    #[test]
+    fn encodes_known_record() {
+        assert_eq!(
+            encode(&[0x01, 0x02], 0x1000, "").as_deref(),
+            Some("S0030000FC\nS10510000102E7\nS5030001FB\nS9031000EC\n")
+        );
+    }
+
+    #[test]
+    fn round_trips_16_bit_addresses() {
+        let data: Vec<u8> = (0..100).collect();
+        let (decoded, kinds) = round_trip(&data, 0x1000);
+        assert_eq!(decoded, data);
+        assert_eq!(kinds, vec![0, 1, 1, 1, 1, 5, 9]);
+    }
+
+    #[test]
+    fn round_trips_24_bit_addresses() {
+        let data = vec![0xaa; 64];
+        let (decoded, kinds) = round_trip(&data, 0x0001_fff0);
+        assert_eq!(decoded, data);
+        assert_eq!(kinds, vec![0, 2, 2, 5, 8]);
+    }
+
+    #[test]
+    fn round_trips_32_bit_addresses() {
+        let data: Vec<u8> = (0..=255).collect();
+        let (decoded, kinds) = round_trip(&data, 0x0800_0000);
+        assert_eq!(decoded, data);
+        assert_eq!(kinds.first(), Some(&0));
+        assert!(kinds[1..9].iter().all(|kind| *kind == 3));
+        assert_eq!(&kinds[9..], &[5, 7]);
+    }
+
+    #[test]
+    fn uses_s6_for_large_record_counts() {
+        let data = vec![0; BYTES_PER_RECORD * 0x1_0000];
+        let (_, kinds) = round_trip(&data, 0x0800_0000);
+        assert_eq!(&kinds[kinds.len() - 2..], &[6, 7]);
+    }
+
+    #[test]
+    fn rejects_images_past_4_gib() {
+        let data = vec![0; 2 * BYTES_PER_RECORD];
+        let (decoded, _) = round_trip(&data, 0xffff_ffc0);
+        assert_eq!(decoded, data);
+        assert_eq!(encode(&data, 0xffff_ffe0, ""), None);
+    }
+}
@@ -1,79 +1,1232 @@
-use goblin::elf::program_header::PT_LOAD;
-use rusb::GlobalContext;
+use goblin::elf::program_header::{ProgramHeader, PT_LOAD};
+use rusb::{DeviceHandle, GlobalContext};
 
+use crate::checksum::Hash;
+use crate::dfu::Transfer;
+use crate::warnings::Warnings;
+use std::cell::Cell;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
 use std::{fs::File, io::Read};
 
 #[derive(Debug)]
 pub enum UtilError {
     Elf(goblin::error::Error),
     Dfu(dfu_libusb::Error),
+    Usb(rusb::Error),
     File(std::io::Error),
+    Hex(String),
+    /// A raw binary was given without an address and the alt setting has no memory layout.
+    MissingAddress,
+    /// The image does not fit the named memory of the device.
+    Layout(String, crate::layout::LayoutError),
+    /// Several connected devices match the selector.
+    AmbiguousDevices(Vec<rusb::Device<GlobalContext>>),
+    /// Two loadable segments of the ELF share addresses.
+    Overlap {
+        address: u64,
+        previous_end: u64,
+    },
+    /// The device reported an error status after a failed download.
+    DfuStatus {
+        status: dfu_core::Status,
+        state: dfu_core::State,
+        /// The string the device describes the status with, if any.
+        description: Option<String>,
+    },
+    /// The device disappeared after `written` of `total` bytes, before the download was complete.
+    DeviceLost {
+        written: usize,
+        total: usize,
+    },
+    /// The data read back differs from the image, starting at `offset`.
+    VerifyMismatch {
+        offset: usize,
+        /// Name of the hash, and checksums of the image and of the data read back.
+        hash: &'static str,
+        expected: String,
+        actual: String,
+    },
+    /// No DFU alt setting of the device has the given name.
+    InterfaceNotFound(String),
+    /// Erasing was asked of a device which does not speak DfuSe.
+    NotDfuSe,
+    /// The image at `start..end` does not fit the flash of the chip.
+    OutsideFlash {
+        start: u64,
+        end: u64,
+        flash: Range<u64>,
+    },
 }
 
-/// Returns a contiguous bin with 0s between non-contiguous sections and starting address from an elf.
-pub fn elf_to_bin(path: PathBuf) -> Result<(Vec<u8>, u32), UtilError> {
+impl UtilError {
+    /// Stable name of the kind of error, for machine readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            UtilError::Elf(_) => "elf",
+            UtilError::Dfu(_) => "dfu",
+            UtilError::Usb(_) => "usb",
+            UtilError::File(_) => "file",
+            UtilError::Hex(_) => "hex",
+            UtilError::MissingAddress => "missing_address",
+            UtilError::Layout(..) => "layout",
+            UtilError::AmbiguousDevices(_) => "ambiguous_devices",
+            UtilError::Overlap { .. } => "overlap",
+            UtilError::DfuStatus { .. } => "dfu_status",
+            UtilError::DeviceLost { .. } => "device_lost",
+            UtilError::VerifyMismatch { .. } => "verify_mismatch",
+            UtilError::OutsideFlash { .. } => "outside_flash",
+            UtilError::NotDfuSe => "not_dfuse",
+            UtilError::InterfaceNotFound(_) => "interface_name",
+        }
+    }
+}
+
+impl std::fmt::Display for UtilError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UtilError::Elf(e) => write!(f, "invalid ELF file: {}", e),
+            UtilError::Dfu(e) => write!(f, "DFU error: {}", e),
+            UtilError::Usb(rusb::Error::Access) => write!(
+                f,
+                "USB error: {}, check the permissions of the device (e.g. its udev rule)",
+                rusb::Error::Access
+            ),
+            UtilError::Usb(e) => write!(f, "USB error: {}", e),
+            UtilError::File(e) => write!(f, "could not read file: {}", e),
+            UtilError::Hex(e) => write!(f, "invalid Intel HEX file: {}", e),
+            UtilError::MissingAddress => write!(f, "a raw binary has no address"),
+            UtilError::Layout(name, e) => write!(f, "image does not fit {}: {}", name, e),
+            UtilError::AmbiguousDevices(devices) => {
+                write!(f, "{} devices match", devices.len())
+            }
+            UtilError::Overlap {
+                address,
+                previous_end,
+            } => write!(
+                f,
+                "segment at {:#x} overlaps the previous one ending at {:#x}",
+                address, previous_end
+            ),
+            UtilError::DfuStatus {
+                status,
+                state,
+                description,
+            } => {
+                write!(
+                    f,
+                    "device reported {} in {}: {}",
+                    crate::dfu::status_name(*status),
+                    crate::dfu::state_name(*state),
+                    description.clone().unwrap_or_else(|| status.to_string())
+                )
+            }
+            UtilError::DeviceLost { written, total } => write!(
+                f,
+                "the device disappeared after {} of {} bytes were written",
+                written, total
+            ),
+            UtilError::VerifyMismatch {
+                offset,
+                hash,
+                expected,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "flash content differs from the image at offset {:#x}, {} {} expected but {} read back",
+                    offset, hash, expected, actual
+                )
+            }
+            UtilError::InterfaceNotFound(name) => {
+                write!(f, "no DFU interface named {:?}, see --list-alts", name)
+            }
+            UtilError::NotDfuSe => write!(
+                f,
+                "erasing needs a DfuSe device, which describes its memory layout"
+            ),
+            UtilError::OutsideFlash { start, end, flash } => write!(
+                f,
+                "image {:#010x}..{:#010x} does not fit the flash at {:#010x}..{:#010x}",
+                start, end, flash.start, flash.end
+            ),
+        }
+    }
+}
+
+/// Checks that `len` bytes at `address` fit `flash`, e.g. the flash of the probe-rs chip.
+pub fn check_flash_region(address: u32, len: usize, flash: &Range<u64>) -> Result<(), UtilError> {
+    let (start, end) = (u64::from(address), u64::from(address) + len as u64);
+    if start < flash.start || end > flash.end {
+        return Err(UtilError::OutsideFlash {
+            start,
+            end,
+            flash: flash.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Which of the program header addresses to place the segments at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSource {
+    Physical,
+    Virtual,
+    /// Physical addresses, unless the linker left them all zero.
+    Auto,
+}
+
+impl std::str::FromStr for AddressSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "physical" => Ok(AddressSource::Physical),
+            "virtual" => Ok(AddressSource::Virtual),
+            "auto" => Ok(AddressSource::Auto),
+            _ => Err(format!("unknown address source {:?}", s)),
+        }
+    }
+}
+
+/// Gaps between segments above this size are reported, they are most likely a linker script
+/// mistake which needlessly inflates the image.
+const LARGE_GAP: u64 = 0x1_0000;
+
+/// Returns a contiguous bin with `fill` between non-contiguous sections and starting address from
+/// an elf.
+pub fn elf_to_bin(
+    path: PathBuf,
+    source: AddressSource,
+    fill: u8,
+    warnings: &mut Warnings,
+) -> Result<(Vec<u8>, u32), UtilError> {
     let mut file = File::open(path).map_err(UtilError::File)?;
     let mut buffer = vec![];
     file.read_to_end(&mut buffer).map_err(UtilError::File)?;
 
-    let binary = goblin::elf::Elf::parse(buffer.as_slice()).map_err(UtilError::Elf)?;
+    elf_bytes_to_image(&buffer, source, fill, warnings).map(Image::into_bin)
+}
+
+fn elf_bytes_to_image(
+    buffer: &[u8],
+    source: AddressSource,
+    fill: u8,
+    warnings: &mut Warnings,
+) -> Result<Image, UtilError> {
+    let binary = goblin::elf::Elf::parse(buffer).map_err(UtilError::Elf)?;
+
+    let (mut segments, skipped): (Vec<_>, Vec<_>) = binary
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD && ph.p_filesz > 0)
+        .partition(|ph| ph.p_offset >= u64::from(binary.header.e_ehsize) && ph.is_read());
+    for ph in skipped {
+        warnings.push(format!(
+            "skipped loadable segment at {:#x} ({} bytes), it overlaps the ELF header or is not readable",
+            ph.p_paddr, ph.p_filesz
+        ));
+    }
+
+    let virtual_addresses = match source {
+        AddressSource::Physical => false,
+        AddressSource::Virtual => true,
+        AddressSource::Auto => segments.iter().all(|ph| ph.p_paddr == 0),
+    };
+    if virtual_addresses {
+        log::info!("Placing segments at their virtual addresses");
+    } else {
+        log::info!("Placing segments at their physical addresses");
+    }
+    let address = |ph: &ProgramHeader| {
+        if virtual_addresses {
+            ph.p_vaddr
+        } else {
+            ph.p_paddr
+        }
+    };
+
+    segments.sort_by_key(|ph| address(ph));
 
     let mut start_address: u64 = 0;
+    // End of the file data of the previous segment, and of its zero initialized tail (.bss), which
+    // is written as zeros if another segment follows and not written otherwise. The tail only takes
+    // room at the virtual address, placed at its load address a segment ends with its file data.
     let mut last_address: u64 = 0;
+    let mut last_memory_end: u64 = 0;
 
     let mut data = vec![];
-    for (i, ph) in binary
-        .program_headers
-        .iter()
-        .filter(|ph| {
-            ph.p_type == PT_LOAD
-                && ph.p_filesz > 0
-                && ph.p_offset >= u64::from(binary.header.e_ehsize)
-                && ph.is_read()
-        })
-        .enumerate()
-    {
+    let mut regions = 0;
+    for (i, ph) in segments.into_iter().enumerate() {
+        // the header sizes are not checked by goblin, so a truncated file must not make us panic
+        let segment = usize::try_from(ph.p_offset)
+            .ok()
+            .zip(usize::try_from(ph.p_filesz).ok())
+            .and_then(|(offset, size)| buffer.get(offset..offset.checked_add(size)?))
+            .ok_or_else(|| {
+                UtilError::Elf(goblin::error::Error::Malformed(format!(
+                    "segment at {:#x} extends past end of file",
+                    address(ph)
+                )))
+            })?;
+
         // first time through grab the starting physical address
         if i == 0 {
-            start_address = ph.p_paddr;
+            start_address = address(ph);
         }
         // on subsequent passes, if there's a gap between this section and the
-        // previous one, fill it with zeros
+        // previous one, fill it with `fill`
         else {
-            let difference = (ph.p_paddr - last_address) as usize;
-            data.resize(data.len() + difference, 0x0);
+            // The zero initialized tail is memory of the previous segment just as well.
+            if address(ph) < last_memory_end {
+                return Err(UtilError::Overlap {
+                    address: address(ph),
+                    previous_end: last_memory_end,
+                });
+            }
+            if address(ph).saturating_sub(last_memory_end) > LARGE_GAP {
+                warnings.push(format!(
+                    "{:#x} byte gap before the segment at {:#x} is filled with {:#04x}",
+                    address(ph) - last_memory_end,
+                    address(ph),
+                    fill
+                ));
+            }
+            // The tail must stay zero, only the gap after it is free to fill.
+            let tail = (last_memory_end - last_address) as usize;
+            data.resize(data.len() + tail, 0x0);
+            let gap = (address(ph) - last_memory_end) as usize;
+            data.resize(data.len() + gap, fill);
+        }
+        if i == 0 || address(ph) > last_address {
+            regions += 1;
         }
 
-        data.extend_from_slice(&buffer[ph.p_offset as usize..][..ph.p_filesz as usize]);
+        data.extend_from_slice(segment);
 
-        last_address = ph.p_paddr + ph.p_filesz;
+        last_address = address(ph).saturating_add(ph.p_filesz);
+        let memory_size = if virtual_addresses {
+            ph.p_memsz.max(ph.p_filesz)
+        } else {
+            ph.p_filesz
+        };
+        last_memory_end = address(ph).saturating_add(memory_size);
     }
 
-    Ok((
+    Ok(Image {
         data,
-        u32::try_from(start_address)
-            .map_err(|e| UtilError::Elf(goblin::error::Error::Malformed(e.to_string())))?,
-    ))
-}
-
-pub fn flash_bin(binary: &[u8], d: &rusb::Device<GlobalContext>) -> Result<(), UtilError> {
-    let mut dfu = dfu_libusb::DfuLibusb::open(
-        &rusb::Context::new().unwrap(),
-        d.device_descriptor().unwrap().vendor_id(),
-        d.device_descriptor().unwrap().product_id(),
-        0,
-        0,
-    )
-    .map_err(UtilError::Dfu)?;
-
-    dfu.download_from_slice(binary).map_err(UtilError::Dfu)?;
-    Ok(())
+        address: Some(
+            u32::try_from(start_address)
+                .map_err(|e| UtilError::Elf(goblin::error::Error::Malformed(e.to_string())))?,
+        ),
+        regions,
+    })
+}
+
+/// A contiguous image ready to be flashed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Image {
+    pub data: Vec<u8>,
+    /// Address of the first byte, raw binaries carry none.
+    pub address: Option<u32>,
+    /// Number of contiguous regions of the source file, the gaps between them being filled.
+    pub regions: usize,
+}
+
+impl Image {
+    fn into_bin(self) -> (Vec<u8>, u32) {
+        (self.data, self.address.unwrap_or(0))
+    }
+}
+
+/// Returns what looks wrong with an image that is about to be flashed, typically because it was
+/// built for the host target or with a broken linker script.
+pub fn check_image(image: &Image, max_size: usize) -> Vec<String> {
+    let mut problems = vec![];
+    if image.regions == 0 {
+        problems.push("the image has no loadable data".to_string());
+    }
+    if image.data.len() > max_size {
+        problems.push(format!(
+            "the image is {} bytes, more than the maximum of {}",
+            image.data.len(),
+            max_size
+        ));
+    }
+    if image.address == Some(0) && !image.data.is_empty() {
+        problems.push("the image starts at address 0".to_string());
+    }
+    problems
+}
+
+/// Returns the image of an ELF whose gaps are filled with `elf_fill` or, going by the extension of
+/// `path`, an Intel HEX file whose gaps are filled with `hex_fill` or a raw binary.
+///
+/// Raw binaries are returned verbatim and carry no address.
+pub fn image_to_bin(
+    path: PathBuf,
+    source: AddressSource,
+    hex_fill: u8,
+    elf_fill: u8,
+    warnings: &mut Warnings,
+) -> Result<Image, UtilError> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("hex" | "ihex") => {
+            let text = std::fs::read_to_string(path).map_err(UtilError::File)?;
+            hex_str_to_image(&text, hex_fill)
+        }
+        Some("bin") => {
+            let data = std::fs::read(path).map_err(UtilError::File)?;
+            Ok(Image {
+                regions: usize::from(!data.is_empty()),
+                data,
+                address: None,
+            })
+        }
+        _ => {
+            let buffer = std::fs::read(path).map_err(UtilError::File)?;
+            elf_bytes_to_image(&buffer, source, elf_fill, warnings)
+        }
+    }
+}
+
+/// Returns a contiguous bin with `fill` between non-contiguous records and starting address from
+/// an Intel HEX file.
+pub fn hex_to_bin(path: PathBuf, fill: u8) -> Result<(Vec<u8>, u32), UtilError> {
+    let text = std::fs::read_to_string(path).map_err(UtilError::File)?;
+    hex_str_to_image(&text, fill).map(Image::into_bin)
+}
+
+fn hex_str_to_image(text: &str, fill: u8) -> Result<Image, UtilError> {
+    let mut base = 0;
+    let mut records = vec![];
+    for record in ihex::Reader::new(text) {
+        match record.map_err(|e| UtilError::Hex(e.to_string()))? {
+            ihex::Record::Data { offset, value } => records.push((base + u64::from(offset), value)),
+            ihex::Record::ExtendedSegmentAddress(segment) => base = u64::from(segment) << 4,
+            ihex::Record::ExtendedLinearAddress(upper) => base = u64::from(upper) << 16,
+            _ => {}
+        }
+    }
+    records.sort_by_key(|(address, _)| *address);
+
+    let start_address = records.first().map_or(0, |(address, _)| *address);
+    let mut data = vec![];
+    let mut regions = 0;
+    for (address, value) in records {
+        let offset = (address - start_address) as usize;
+        if offset < data.len() {
+            return Err(UtilError::Hex(format!(
+                "record at {:#x} overlaps the previous one",
+                address
+            )));
+        }
+        if offset > data.len() || regions == 0 {
+            regions += 1;
+        }
+        data.resize(offset, fill);
+        data.extend_from_slice(&value);
+    }
+
+    let end = start_address + data.len() as u64;
+    if end > 1 << 32 {
+        return Err(UtilError::Hex(format!(
+            "data extends past the 32 bit address space up to {:#x}",
+            end
+        )));
+    }
+
+    Ok(Image {
+        data,
+        address: Some(start_address as u32),
+        regions,
+    })
+}
+
+/// Downloads `binary` to `address`, rather than to the start of the memory described by the alt
+/// setting, calling `progress` with the number of bytes written by every transfer.
+///
+/// Devices which leave DFU mode right after manifestation can't be told apart from lost ones by
+/// their error, so the download only succeeds if every byte was written before.
+pub fn flash_bin(
+    binary: &[u8],
+    address: u32,
+    d: &rusb::Device<GlobalContext>,
+    interface: u8,
+    alt: u8,
+    transfer: Transfer,
+    progress: impl FnMut(usize) + 'static,
+) -> Result<(), UtilError> {
+    let mut handle = d.open().map_err(UtilError::Usb)?;
+    // The device does not accept data until a previous failed or interrupted run is cleared.
+    if let Some(state) =
+        crate::dfu::recover_idle(&mut handle, interface, alt).map_err(UtilError::Dfu)?
+    {
+        log::warn!(
+            "The device was in {}, returned it to dfuIDLE before downloading",
+            crate::dfu::state_name(state)
+        );
+    }
+    let mut dfu = crate::dfu::open(handle, interface, alt, transfer).map_err(UtilError::Dfu)?;
+
+    let written = Rc::new(Cell::new(0));
+    let mut progress = progress;
+    let counter = written.clone();
+    match dfu
+        .with_progress(move |n| {
+            counter.set(counter.get() + n);
+            progress(n)
+        })
+        .override_address(address)
+        .download_from_slice(binary)
+    {
+        Ok(()) => Ok(()),
+        // The device left DFU mode, there is nobody left to ask.
+        Err(dfu_libusb::Error::LibUsb(rusb::Error::NoDevice)) if written.get() >= binary.len() => {
+            Ok(())
+        }
+        Err(dfu_libusb::Error::LibUsb(rusb::Error::NoDevice)) => Err(UtilError::DeviceLost {
+            written: written.get(),
+            total: binary.len(),
+        }),
+        Err(e) => {
+            // Release our claim on the interface before asking again.
+            drop(dfu);
+            Err(download_error(d, interface).unwrap_or(UtilError::Dfu(e)))
+        }
+    }
+}
+
+/// Asks the device why a download failed, if it reports an error status.
+fn download_error(d: &rusb::Device<GlobalContext>, interface: u8) -> Option<UtilError> {
+    let mut handle = d.open().ok()?;
+    handle.claim_interface(interface).ok()?;
+    let status = crate::dfu::get_status(&handle, interface).ok()?;
+    if status.status == dfu_core::Status::Ok {
+        return None;
+    }
+
+    let description = match status.string_index {
+        0 => None,
+        index => handle.read_string_descriptor_ascii(index).ok(),
+    };
+    Some(UtilError::DfuStatus {
+        status: status.status,
+        state: status.state,
+        description,
+    })
+}
+
+/// An alt setting of a DFU interface and its string descriptor.
+pub struct AltSetting {
+    pub interface: u8,
+    pub alt: u8,
+    pub name: String,
+}
+
+/// Lists the alt settings of all DFU interfaces of the active configuration.
+pub fn alt_settings(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<Vec<AltSetting>> {
+    let timeout = Duration::from_secs(1);
+    let device = handle.device();
+    let languages = handle.read_languages(timeout)?;
+    let language = languages.first().ok_or(rusb::Error::NotFound)?;
+    let config = device.active_config_descriptor()?;
+
+    let mut alts = vec![];
+    for descriptor in config.interfaces().flat_map(|i| i.descriptors()) {
+        if (descriptor.class_code(), descriptor.sub_class_code()) != crate::dfu::DFU_CLASS {
+            continue;
+        }
+        alts.push(AltSetting {
+            interface: descriptor.interface_number(),
+            alt: descriptor.setting_number(),
+            name: handle
+                .read_interface_string(*language, &descriptor, timeout)
+                .unwrap_or_default(),
+        });
+    }
+
+    Ok(alts)
+}
+
+/// Returns the interface and alt setting of the first DFU alt setting whose name contains `name`.
+pub fn find_alt_setting(
+    handle: &DeviceHandle<GlobalContext>,
+    name: &str,
+) -> Result<(u8, u8), UtilError> {
+    alt_settings(handle)
+        .map_err(UtilError::Usb)?
+        .into_iter()
+        .find(|alt| alt.name.contains(name))
+        .map(|alt| (alt.interface, alt.alt))
+        .ok_or_else(|| UtilError::InterfaceNotFound(name.to_string()))
+}
+
+/// When to read the flash back and compare it with the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyMode {
+    /// Right after the download, re-opening the device if it reset itself after manifestation.
+    Immediate,
+    /// After resetting the device and waiting for it to re-enter DFU mode, to be sure the data
+    /// was committed to flash.
+    AfterReset,
+}
+
+impl std::str::FromStr for VerifyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(VerifyMode::Immediate),
+            "after-reset" => Ok(VerifyMode::AfterReset),
+            _ => Err(format!("unknown verify mode {:?}", s)),
+        }
+    }
+}
+
+/// Reads the image back from the device and compares it with `binary`, along with their `hash`
+/// checksums.
+pub fn verify_bin(
+    binary: &[u8],
+    address: u32,
+    handle: &mut DeviceHandle<GlobalContext>,
+    interface: u8,
+    alt: u8,
+    hash: Hash,
+) -> Result<(), UtilError> {
+    let data = crate::dfu::upload(handle, interface, alt, Some(address), Some(binary.len()))
+        .map_err(UtilError::Dfu)?;
+
+    let offset = match binary.iter().zip(&data).position(|(a, b)| a != b) {
+        Some(offset) => offset,
+        None if data.len() < binary.len() => data.len(),
+        None => return Ok(()),
+    };
+    Err(UtilError::VerifyMismatch {
+        offset,
+        hash: hash.name(),
+        expected: hash.digest(binary),
+        actual: hash.digest(&data),
+    })
+}
+
+/// Returns the memory layout of an alt setting, which only DfuSe devices describe.
+pub fn alt_layout(
+    handle: &DeviceHandle<GlobalContext>,
+    interface: u8,
+    alt: u8,
+) -> Option<crate::layout::Layout> {
+    alt_settings(handle)
+        .ok()?
+        .into_iter()
+        .find(|a| a.interface == interface && a.alt == alt)
+        .and_then(|alt| crate::layout::Layout::parse(&alt.name).ok())
+}
+
+/// Claims and releases `interface` to find out whether another process holds it.
+pub fn check_interface_free(
+    handle: &mut DeviceHandle<GlobalContext>,
+    interface: u8,
+) -> rusb::Result<()> {
+    handle.claim_interface(interface)?;
+    handle.release_interface(interface)
+}
+
+/// Returns the manufacturer and product strings of the device behind `handle`, leaving out those
+/// it does not have.
+pub fn product_name(handle: &DeviceHandle<GlobalContext>) -> String {
+    let Ok(descriptor) = handle.device().device_descriptor() else {
+        return "unknown device".to_string();
+    };
+    let strings: Vec<_> = [
+        handle.read_manufacturer_string_ascii(&descriptor),
+        handle.read_product_string_ascii(&descriptor),
+    ]
+    .into_iter()
+    .filter_map(Result::ok)
+    .collect();
+
+    if strings.is_empty() {
+        format!(
+            "{:04x}:{:04x}",
+            descriptor.vendor_id(),
+            descriptor.product_id()
+        )
+    } else {
+        strings.join(" ")
+    }
+}
+
+/// Reads the serial number string of the device behind `handle`, if it has one.
+pub fn serial_number(handle: &DeviceHandle<GlobalContext>) -> Option<String> {
+    let descriptor = handle.device().device_descriptor().ok()?;
+    handle.read_serial_number_string_ascii(&descriptor).ok()
+}
+
+/// Returns a stable, OS-specific path of the device, derived from its bus and port numbers.
+///
+/// On Linux this is the sysfs path, on macOS the IORegistry location id and on Windows the
+/// device instance id, which can only be derived for devices with a serial number.
+pub fn device_path(handle: &DeviceHandle<GlobalContext>) -> String {
+    let device = handle.device();
+    let ports = device.port_numbers().unwrap_or_default();
+    os_device_path(handle, device.bus_number(), &ports)
+}
+
+#[cfg(target_os = "linux")]
+fn os_device_path(_: &DeviceHandle<GlobalContext>, bus: u8, ports: &[u8]) -> String {
+    if ports.is_empty() {
+        return format!("/sys/bus/usb/devices/usb{}", bus);
+    }
+    let ports: Vec<_> = ports.iter().map(u8::to_string).collect();
+    format!("/sys/bus/usb/devices/{}-{}", bus, ports.join("."))
+}
+
+#[cfg(target_os = "macos")]
+fn os_device_path(_: &DeviceHandle<GlobalContext>, bus: u8, ports: &[u8]) -> String {
+    // The location id has the bus in its top byte followed by one nibble per port.
+    let location = ports
+        .iter()
+        .take(6)
+        .enumerate()
+        .fold(u32::from(bus) << 24, |location, (i, port)| {
+            location | (u32::from(*port & 0xf) << (20 - 4 * i))
+        });
+    format!("{:#010x}", location)
+}
+
+#[cfg(target_os = "windows")]
+fn os_device_path(handle: &DeviceHandle<GlobalContext>, bus: u8, ports: &[u8]) -> String {
+    let descriptor = handle.device().device_descriptor();
+    let serial = descriptor
+        .as_ref()
+        .ok()
+        .and_then(|d| handle.read_serial_number_string_ascii(d).ok());
+    match (descriptor, serial) {
+        (Ok(d), Some(serial)) => format!(
+            "USB\\VID_{:04X}&PID_{:04X}\\{}",
+            d.vendor_id(),
+            d.product_id(),
+            serial
+        ),
+        _ => generic_device_path(bus, ports),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn os_device_path(_: &DeviceHandle<GlobalContext>, bus: u8, ports: &[u8]) -> String {
+    generic_device_path(bus, ports)
+}
+
+/// `<bus>-<port>.<port>...`, the form used by libusb and dfu-util.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn generic_device_path(bus: u8, ports: &[u8]) -> String {
+    let ports: Vec<_> = ports.iter().map(u8::to_string).collect();
+    format!("{}-{}", bus, ports.join("."))
+}
+
+/// Physical location of a device: the chain of hub port numbers leading to it, optionally on a
+/// given bus, written `1.4.2` or `3-1.4.2` like libusb and dfu-util do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortPath {
+    pub bus: Option<u8>,
+    pub ports: Vec<u8>,
+}
+
+impl PortPath {
+    /// Whether `device` is plugged in at this path.
+    pub fn matches(&self, device: &rusb::Device<GlobalContext>) -> bool {
+        self.bus.is_none_or(|bus| bus == device.bus_number())
+            && device.port_numbers().is_ok_and(|ports| ports == self.ports)
+    }
+}
+
+impl std::str::FromStr for PortPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port path {:?}, expected e.g. 1.4.2 or 3-1.4.2", s);
+        let (bus, ports) = match s.split_once('-') {
+            Some((bus, ports)) => (Some(bus.parse().map_err(|_| invalid())?), ports),
+            None => (None, s),
+        };
+        let ports = ports
+            .split('.')
+            .map(|port| port.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<u8>, _>>()?;
+
+        Ok(PortPath { bus, ports })
+    }
+}
+
+impl std::fmt::Display for PortPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(bus) = self.bus {
+            write!(f, "{}-", bus)?;
+        }
+        let ports: Vec<_> = self.ports.iter().map(u8::to_string).collect();
+        write!(f, "{}", ports.join("."))
+    }
 }
 
 pub fn vendor_map() -> std::collections::HashMap<String, Vec<(u16, u16)>> {
     maplit::hashmap! {
+        // The DfuSe bootloader in the system memory of STM32 chips, every family from the F0 to the
+        // H7 enumerates with the same ids.
         "stm32".to_string() => vec![(0x0483, 0xdf11)],
         "gd32vf103".to_string() =>  vec![(0x28e9, 0x0189)],
+        // dapboot, a DFU bootloader for STM32F1 boards such as the blue pill.
+        "dapboot".to_string() => vec![(0x1209, 0xdb42)],
+        // The DFU bootloader of the Black Magic Probe.
+        "blackmagic".to_string() => vec![(0x1d50, 0x6017)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A loadable segment of a test ELF.
+    struct Segment {
+        paddr: u32,
+        vaddr: u32,
+        data: Vec<u8>,
+        /// Zero initialized bytes following the data in memory.
+        bss: u32,
+    }
+
+    impl Segment {
+        fn new(paddr: u32, data: &[u8]) -> Self {
+            Segment {
+                paddr,
+                vaddr: paddr,
+                data: data.to_vec(),
+                bss: 0,
+            }
+        }
+    }
+
+    /// Builds a little endian ELF32 with one readable PT_LOAD program header per segment.
+    fn elf(segments: &[Segment]) -> Vec<u8> {
+        const EHSIZE: u32 = 52;
+        const PHENTSIZE: u32 = 32;
+
+        let mut buffer = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        buffer.resize(16, 0);
+        for half in [2u16, 40] {
+            buffer.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1u32, 0, EHSIZE, 0, 0] {
+            buffer.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [
+            EHSIZE as u16,
+            PHENTSIZE as u16,
+            segments.len() as u16,
+            40,
+            0,
+            0,
+        ] {
+            buffer.extend_from_slice(&half.to_le_bytes());
+        }
+
+        let mut offset = EHSIZE + PHENTSIZE * segments.len() as u32;
+        for segment in segments {
+            let size = segment.data.len() as u32;
+            let header = [
+                1,
+                offset,
+                segment.vaddr,
+                segment.paddr,
+                size,
+                size + segment.bss,
+                4,
+                4,
+            ];
+            for word in header {
+                buffer.extend_from_slice(&word.to_le_bytes());
+            }
+            offset += size;
+        }
+        for segment in segments {
+            buffer.extend_from_slice(&segment.data);
+        }
+
+        buffer
+    }
+
+    #[test]
+    fn converts_contiguous_segments() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0000, &[1, 2, 3, 4]),
+            Segment::new(0x0800_0004, &[5, 6]),
+        ]);
+        let Image { data, address, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()).unwrap();
+        assert_eq!(address, Some(0x0800_0000));
+        assert_eq!(data, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_segment_past_end_of_file() {
+        let mut buffer = elf(&[Segment::new(0x0800_0000, &[1, 2, 3, 4])]);
+        buffer.truncate(buffer.len() - 1);
+        match elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()) {
+            Err(UtilError::Elf(goblin::error::Error::Malformed(message))) => {
+                assert!(message.contains("past end of file"), "{}", message)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_huge_segment_offset() {
+        let mut buffer = elf(&[Segment::new(0x0800_0000, &[1, 2, 3, 4])]);
+        // p_offset of the first program header
+        buffer[56..60].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()),
+            Err(UtilError::Elf(goblin::error::Error::Malformed(_)))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_program_headers() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0000, &[1, 2, 3, 4]),
+            Segment::new(0x0800_0004, &[5, 6]),
+        ]);
+        for len in [0, 20, 52, 60, 100] {
+            assert!(
+                elf_bytes_to_image(
+                    &buffer[..len],
+                    AddressSource::Auto,
+                    0,
+                    &mut Warnings::default()
+                )
+                .is_err(),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn uses_physical_addresses_when_populated() {
+        let mut segment = Segment::new(0x0800_0000, &[1, 2]);
+        segment.vaddr = 0x2000_0000;
+        let buffer = elf(&[segment]);
+        for source in [AddressSource::Auto, AddressSource::Physical] {
+            let Image { address, .. } =
+                elf_bytes_to_image(&buffer, source, 0, &mut Warnings::default()).unwrap();
+            assert_eq!(address, Some(0x0800_0000));
+        }
+        let Image { address, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Virtual, 0, &mut Warnings::default())
+                .unwrap();
+        assert_eq!(address, Some(0x2000_0000));
+    }
+
+    #[test]
+    fn falls_back_to_virtual_addresses() {
+        let mut first = Segment::new(0, &[1, 2]);
+        first.vaddr = 0x0800_0000;
+        let mut second = Segment::new(0, &[3, 4]);
+        second.vaddr = 0x0800_0004;
+        let buffer = elf(&[first, second]);
+
+        let Image { data, address, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()).unwrap();
+        assert_eq!(address, Some(0x0800_0000));
+        assert_eq!(data, vec![1, 2, 0, 0, 3, 4]);
+    }
+
+    #[test]
+    fn sorts_segments_by_address() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0008, &[5, 6]),
+            Segment::new(0x0800_0000, &[1, 2, 3, 4]),
+        ]);
+        let Image { data, address, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()).unwrap();
+        assert_eq!(address, Some(0x0800_0000));
+        assert_eq!(data, vec![1, 2, 3, 4, 0, 0, 0, 0, 5, 6]);
+    }
+
+    #[test]
+    fn fills_gaps_with_the_fill_byte() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0000, &[1, 2]),
+            Segment::new(0x0800_0004, &[3, 4]),
+        ]);
+        let Image { data, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0xff, &mut Warnings::default())
+                .unwrap();
+        assert_eq!(data, vec![1, 2, 0xff, 0xff, 3, 4]);
+    }
+
+    #[test]
+    fn keeps_bss_tails_zero_when_filling() {
+        let mut data = Segment::new(0x0800_0000, &[1, 2]);
+        data.bss = 2;
+        let buffer = elf(&[data, Segment::new(0x0800_0008, &[3])]);
+        let Image { data, .. } = elf_bytes_to_image(
+            &buffer,
+            AddressSource::Virtual,
+            0xff,
+            &mut Warnings::default(),
+        )
+        .unwrap();
+        assert_eq!(data, vec![1, 2, 0, 0, 0xff, 0xff, 0xff, 0xff, 3]);
+    }
+
+    #[test]
+    fn counts_contiguous_regions() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0000, &[1, 2]),
+            Segment::new(0x0800_0002, &[3, 4]),
+            Segment::new(0x0800_0100, &[5, 6]),
+        ]);
+        let image =
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()).unwrap();
+        assert_eq!(image.regions, 2);
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0000, &[1, 2, 3, 4]),
+            Segment::new(0x0800_0002, &[5, 6]),
+        ]);
+        assert!(matches!(
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut Warnings::default()),
+            Err(UtilError::Overlap {
+                address: 0x0800_0002,
+                previous_end: 0x0800_0004
+            })
+        ));
+
+        // Both segments at physical address 0 used to underflow.
+        let mut first = Segment::new(0, &[1, 2]);
+        first.vaddr = 0x0800_0000;
+        let mut second = Segment::new(0, &[3, 4]);
+        second.vaddr = 0x0800_0004;
+        let buffer = elf(&[first, second]);
+        assert!(matches!(
+            elf_bytes_to_image(
+                &buffer,
+                AddressSource::Physical,
+                0,
+                &mut Warnings::default()
+            ),
+            Err(UtilError::Overlap { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_segments_inside_bss_tails() {
+        let mut data = Segment::new(0x0800_0000, &[1, 2]);
+        data.bss = 0x100;
+        let buffer = elf(&[data, Segment::new(0x0800_0010, &[3])]);
+        assert!(matches!(
+            elf_bytes_to_image(&buffer, AddressSource::Virtual, 0, &mut Warnings::default()),
+            Err(UtilError::Overlap {
+                address: 0x0800_0010,
+                previous_end: 0x0800_0102
+            })
+        ));
+    }
+
+    #[test]
+    fn ignores_bss_tails_at_load_addresses() {
+        // .data is loaded from flash right after .text, its tail only takes room in RAM.
+        let mut data = Segment::new(0x0800_0100, &[1, 2]);
+        data.vaddr = 0x2000_0000;
+        data.bss = 0x100;
+        let buffer = elf(&[data, Segment::new(0x0800_0102, &[3])]);
+        let Image { data, address, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Auto, 0xff, &mut Warnings::default())
+                .unwrap();
+        assert_eq!((data, address), (vec![1, 2, 3], Some(0x0800_0100)));
+    }
+
+    #[test]
+    fn zero_fills_bss_tails() {
+        let mut data = Segment::new(0x0800_0000, &[1, 2]);
+        data.bss = 0x2_0000;
+        let buffer = elf(&[data, Segment::new(0x0802_0002, &[3])]);
+        let mut warnings = Warnings::default();
+        let Image { data, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Virtual, 0, &mut warnings).unwrap();
+        assert_eq!(data.len(), 0x2_0003);
+        assert_eq!(
+            (data[0], data[1], data[0x2_0001], data[0x2_0002]),
+            (1, 2, 0, 3)
+        );
+        // The tail is part of the segment, not a gap.
+        assert_eq!(warnings.iter().count(), 0);
+
+        // Nor is it written after the last segment.
+        let mut data = Segment::new(0x0800_0000, &[1, 2]);
+        data.bss = 0x100;
+        let Image { data, .. } = elf_bytes_to_image(
+            &elf(&[data]),
+            AddressSource::Virtual,
+            0,
+            &mut Warnings::default(),
+        )
+        .unwrap();
+        assert_eq!(data, vec![1, 2]);
+    }
+
+    #[test]
+    fn warns_about_large_gaps() {
+        let buffer = elf(&[Segment::new(0, &[1]), Segment::new(0x2_0000, &[2])]);
+        let mut warnings = Warnings::default();
+        let Image { data, address, .. } =
+            elf_bytes_to_image(&buffer, AddressSource::Physical, 0, &mut warnings).unwrap();
+        assert_eq!((data.len(), address), (0x2_0001, Some(0)));
+
+        let warnings: Vec<_> = warnings.iter().collect();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("gap"));
+    }
+
+    #[test]
+    fn checks_the_flash_region() {
+        let flash = 0x0800_0000..0x0801_0000;
+        assert!(check_flash_region(0x0800_0000, 0x1_0000, &flash).is_ok());
+        assert!(matches!(
+            check_flash_region(0x0800_8000, 0x1_0000, &flash),
+            Err(UtilError::OutsideFlash {
+                start: 0x0800_8000,
+                end: 0x0801_8000,
+                ..
+            })
+        ));
+        assert!(check_flash_region(0x0000_0000, 1, &flash).is_err());
+    }
+
+    #[test]
+    fn finds_suspicious_images() {
+        let image = |segments: &[Segment]| {
+            elf_bytes_to_image(
+                &elf(segments),
+                AddressSource::Physical,
+                0,
+                &mut Warnings::default(),
+            )
+            .unwrap()
+        };
+
+        let regular = image(&[Segment::new(0x0800_0000, &[1, 2, 3, 4])]);
+        assert!(check_image(&regular, 4).is_empty());
+
+        let problems = check_image(&regular, 3);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("maximum"));
+
+        let problems = check_image(&image(&[Segment::new(0, &[1])]), 4);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("address 0"));
+
+        let problems = check_image(&image(&[]), 4);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("no loadable"));
+    }
+
+    #[test]
+    fn does_not_warn_about_regular_images() {
+        let buffer = elf(&[
+            Segment::new(0x0800_0000, &[1, 2, 3, 4]),
+            Segment::new(0x0800_0100, &[5, 6]),
+        ]);
+        let mut warnings = Warnings::default();
+        elf_bytes_to_image(&buffer, AddressSource::Auto, 0, &mut warnings).unwrap();
+        assert_eq!(warnings.iter().count(), 0);
+    }
+
+    #[test]
+    fn parses_port_paths() {
+        assert_eq!(
+            "1.4.2".parse(),
+            Ok(PortPath {
+                bus: None,
+                ports: vec![1, 4, 2]
+            })
+        );
+        assert_eq!(
+            "3-1".parse(),
+            Ok(PortPath {
+                bus: Some(3),
+                ports: vec![1]
+            })
+        );
+        assert!("".parse::<PortPath>().is_err());
+        assert!("1..2".parse::<PortPath>().is_err());
+        assert!("1.256".parse::<PortPath>().is_err());
+        assert!("x-1".parse::<PortPath>().is_err());
+    }
+
+    #[test]
+    fn displays_port_paths() {
+        for path in ["1.4.2", "3-1.4.2"] {
+            assert_eq!(path.parse::<PortPath>().unwrap().to_string(), path);
+        }
+    }
+
+    #[test]
+    fn converts_hex_records() {
+        let hex = ":020000040800F2\n\
+                   :0400000001020304F2\n\
+                   :02000800050CE5\n\
+                   :00000001FF\n";
+        let Image { data, address, .. } = hex_str_to_image(hex, 0xff).unwrap();
+        assert_eq!(address, Some(0x0800_0000));
+        assert_eq!(data, vec![1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff, 5, 12]);
+    }
+
+    #[test]
+    fn honors_extended_segment_addresses() {
+        let hex = ":020000021000EC\n\
+                   :0100100042AD\n\
+                   :00000001FF\n";
+        assert_eq!(
+            hex_str_to_image(hex, 0).unwrap(),
+            Image {
+                data: vec![0x42],
+                address: Some(0x1_0010),
+                regions: 1
+            }
+        );
+    }
+
+    #[test]
+    fn sorts_hex_records_and_rejects_overlaps() {
+        let hex = ":0100040002F9\n:0100000001FE\n:00000001FF\n";
+        assert_eq!(
+            hex_str_to_image(hex, 0).unwrap(),
+            Image {
+                data: vec![1, 0, 0, 0, 2],
+                address: Some(0),
+                regions: 2
+            }
+        );
+
+        let hex = ":020000000102FB\n:0100010003FB\n:00000001FF\n";
+        assert!(matches!(hex_str_to_image(hex, 0), Err(UtilError::Hex(_))));
+    }
+
+    #[test]
+    fn rejects_bad_hex_checksums() {
+        let hex = ":0400000001020304F3\n:00000001FF\n";
+        assert!(matches!(hex_str_to_image(hex, 0), Err(UtilError::Hex(_))));
     }
 }
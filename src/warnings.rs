@@ -0,0 +1,34 @@
+//! Non-fatal diagnostics collected during a run and reported together at the end.
+
+use colored::Colorize;
+
+#[derive(Debug, Default)]
+pub struct Warnings(Vec<String>);
+
+impl Warnings {
+    /// Records a diagnostic, also logging it right away for `RUST_LOG` users.
+    pub fn push(&mut self, warning: String) {
+        log::warn!("{}", warning);
+        self.0.push(warning);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// Prints the collected diagnostics, if any.
+    pub fn print(&self) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        println!(
+            "    {} {} issue(s)",
+            "Warnings".yellow().bold(),
+            self.0.len()
+        );
+        for warning in self.iter() {
+            println!("             - {}", warning);
+        }
+    }
+}